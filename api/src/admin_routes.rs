@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use shurbai::types::ModelManager;
+
+use crate::{
+    types::{
+        AdminModel, AdminModelResponse, AdminModelsResponse, AppState, ErrorResponse,
+        LoadModelRequest, ServerMetadata, StatusMessage, UpdateModelRequest,
+    },
+    utils,
+};
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(ErrorResponse::new(message))).into_response()
+}
+
+/// Compare without bailing at the first differing byte, so timing doesn't leak the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Only let requests with `Authorization: Bearer <admin_token>` through
+/// Without an admin token in the config the admin routes don't exist
+pub async fn require_admin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = state.admin_token.as_deref() else {
+        return error(StatusCode::NOT_FOUND, "Admin API is disabled");
+    };
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token"),
+    }
+}
+
+fn admin_model(model_manager: &ModelManager, name: &str) -> Option<AdminModel> {
+    Some(AdminModel {
+        status: model_manager.model_status(name)?,
+        definition: model_manager.definition(name)?,
+    })
+}
+
+fn model_response(model_manager: &ModelManager, status: StatusCode, name: &str) -> Response {
+    match admin_model(model_manager, name) {
+        Some(model) => (
+            status,
+            Json(AdminModelResponse {
+                meta: ServerMetadata::new(),
+                model,
+            }),
+        )
+            .into_response(),
+        // Removed by a config reload in the meantime
+        None => error(StatusCode::NOT_FOUND, "Model not found"),
+    }
+}
+
+pub async fn list_models(State(model_manager): State<Arc<ModelManager>>) -> impl IntoResponse {
+    let models = model_manager
+        .status()
+        .into_iter()
+        .filter_map(|status| admin_model(&model_manager, &status.name))
+        .collect();
+    (
+        StatusCode::OK,
+        Json(AdminModelsResponse {
+            meta: ServerMetadata::new(),
+            models,
+        }),
+    )
+}
+
+/// Load a model without holding up the async worker
+async fn load(model_manager: &Arc<ModelManager>, name: &str) -> anyhow::Result<()> {
+    let name = name.to_string();
+    utils::blocking(model_manager.clone(), move |m| m.get(&name).map(drop)).await
+}
+
+pub async fn get_model(
+    State(model_manager): State<Arc<ModelManager>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    model_response(&model_manager, StatusCode::OK, &name)
+}
+
+pub async fn load_model(
+    State(model_manager): State<Arc<ModelManager>>,
+    Json(request_body): Json<LoadModelRequest>,
+) -> impl IntoResponse {
+    let name = request_body.definition.name.clone();
+    if !model_manager.add(request_body.definition) {
+        return error(
+            StatusCode::CONFLICT,
+            &format!("Model {} already exists, PATCH it to change it", name),
+        );
+    }
+    if request_body.load.unwrap_or(true) {
+        if let Err(e) = load(&model_manager, &name).await {
+            // Don't leave a model behind that can never load
+            model_manager.remove(&name);
+            return error(StatusCode::BAD_REQUEST, &e.to_string());
+        }
+    }
+    model_response(&model_manager, StatusCode::CREATED, &name)
+}
+
+pub async fn update_model(
+    State(model_manager): State<Arc<ModelManager>>,
+    Path(name): Path<String>,
+    Json(request_body): Json<UpdateModelRequest>,
+) -> impl IntoResponse {
+    let Some(previous) = model_manager.definition(&name) else {
+        return error(StatusCode::NOT_FOUND, "Model not found");
+    };
+    let mut definition = previous;
+    if let Some(config) = request_body.config {
+        definition.config = config;
+    }
+    if let Some(chat_template) = request_body.chat_template {
+        definition.chat_template = Some(chat_template);
+    }
+    if let Some(jinja_template) = request_body.jinja_template {
+        definition.jinja_template = Some(jinja_template);
+    }
+    if let Some(template_format) = request_body.template_format {
+        definition.template_format = Some(template_format);
+    }
+    if let Some(lora_adapters) = request_body.lora_adapters {
+        definition.lora_adapters = lora_adapters;
+    }
+    // Requests already running keep the old model, new ones get the new definition
+    let updated = utils::blocking(model_manager.clone(), move |m| m.update(definition)).await;
+    if let Err(e) = updated {
+        return error(StatusCode::BAD_REQUEST, &e.to_string());
+    }
+    model_response(&model_manager, StatusCode::OK, &name)
+}
+
+pub async fn unload_model(
+    State(model_manager): State<Arc<ModelManager>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if !model_manager.unload(&name) {
+        return error(StatusCode::NOT_FOUND, "Model not found");
+    }
+    model_response(&model_manager, StatusCode::OK, &name)
+}
+
+pub async fn delete_model(
+    State(model_manager): State<Arc<ModelManager>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if !model_manager.remove(&name) {
+        return error(StatusCode::NOT_FOUND, "Model not found");
+    }
+    (
+        StatusCode::OK,
+        Json(StatusMessage {
+            message: format!("Model {} removed", name),
+        }),
+    )
+        .into_response()
+}
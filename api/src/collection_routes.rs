@@ -0,0 +1,303 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{Map, Value};
+use shurbai::{
+    embedding_cache::{fnv1a, EmbeddingCache},
+    types::{Capability, EmbeddingOptions, EmbeddingResult, InputType, ModelManager},
+};
+
+use crate::{
+    collections::{CollectionStore, ScoredDocument, StoredDocument},
+    types::{
+        AppState, CollectionInfo, CreateCollectionRequest, ErrorResponse, ListCollectionsResponse,
+        QueryCollectionRequest, QueryCollectionResponse, ServerMetadata, StatusMessage,
+        UpsertDocumentsRequest, UpsertDocumentsResponse,
+    },
+    utils::{self, has_model},
+};
+
+fn error(status: StatusCode, message: &str) -> axum::response::Response {
+    (status, Json(ErrorResponse::new(message))).into_response()
+}
+
+/// Embed `texts` with the collection's model, using the model's own pooling defaults
+/// Loading and embedding both run on the blocking pool
+async fn embed(
+    model_manager: &Arc<ModelManager>,
+    cache: &Arc<EmbeddingCache>,
+    model_name: &String,
+    texts: Vec<String>,
+    input_type: InputType,
+) -> Result<Vec<EmbeddingResult>, (StatusCode, String)> {
+    if !has_model(model_manager, model_name) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Model {} for this collection is not configured", model_name),
+        ));
+    }
+    let cache = cache.clone();
+    let model_name = model_name.clone();
+    utils::blocking(model_manager.clone(), move |model_manager| {
+        // No fallback models here, vectors from another model don't compare with the stored ones
+        let model_state = match model_manager.get(&model_name) {
+            Ok(model_state) => model_state,
+            Err(e) => return Ok(Err((StatusCode::SERVICE_UNAVAILABLE, e.to_string()))),
+        };
+        let options = EmbeddingOptions {
+            input_type: Some(input_type),
+            ..Default::default()
+        };
+        Ok(cache
+            .embed(
+                &model_name,
+                &model_state,
+                &model_manager.backend,
+                &texts,
+                &options,
+            )
+            .map_err(|e| (utils::embedding_error_status(&e), e.to_string())))
+    })
+    .await
+    .unwrap_or_else(|e| Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())))
+}
+
+/// The `top_k` documents closest to `vector` in a collection
+fn search(
+    collections: &CollectionStore,
+    name: &str,
+    vector: &[f32],
+    top_k: usize,
+    filter: Option<&Map<String, Value>>,
+) -> Result<Vec<ScoredDocument>, (StatusCode, String)> {
+    collections
+        .with(name, |collection| match collection.dimensions {
+            Some(dimensions) if dimensions != vector.len() => Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Query has {} dimensions, the collection has {}",
+                    vector.len(),
+                    dimensions
+                ),
+            )),
+            _ => Ok(collection.query(vector, top_k, filter)),
+        })
+        .unwrap_or_else(|| Err((StatusCode::NOT_FOUND, "Collection not found".to_string())))
+}
+
+/// Embed `text` with the collection's model and return the closest documents
+pub async fn retrieve(
+    model_manager: &Arc<ModelManager>,
+    cache: &Arc<EmbeddingCache>,
+    collections: &CollectionStore,
+    name: &str,
+    text: &str,
+    top_k: usize,
+    filter: Option<&Map<String, Value>>,
+) -> Result<Vec<ScoredDocument>, (StatusCode, String)> {
+    let Some(model) = collections.with(name, |c| c.model.clone()) else {
+        return Err((StatusCode::NOT_FOUND, "Collection not found".to_string()));
+    };
+    let mut embeddings = embed(
+        model_manager,
+        cache,
+        &model,
+        vec![text.to_string()],
+        InputType::SearchQuery,
+    )
+    .await?;
+    search(
+        collections,
+        name,
+        &embeddings.remove(0).embedding,
+        top_k,
+        filter,
+    )
+}
+
+/// A hash of the text, stable between builds so re-adding a document replaces it
+fn default_document_id(text: &str) -> String {
+    format!("{:016x}", fnv1a(&[text.as_bytes()]))
+}
+
+pub async fn list_collections(State(state): State<AppState>) -> impl IntoResponse {
+    let collections = state
+        .collections
+        .list()
+        .iter()
+        .map(CollectionInfo::from)
+        .collect();
+    (
+        StatusCode::OK,
+        Json(ListCollectionsResponse {
+            meta: ServerMetadata::new(),
+            collections,
+        }),
+    )
+}
+
+pub async fn create_collection(
+    State(state): State<AppState>,
+    Json(request_body): Json<CreateCollectionRequest>,
+) -> impl IntoResponse {
+    // The collection keeps the model it was routed to, every later embedding has to come from it
+    let model = match utils::route_model(
+        &state.model_manager,
+        Some(&request_body.model),
+        &[Capability::Embedding],
+    ) {
+        Ok(model) => model,
+        Err(message) => return error(StatusCode::NOT_FOUND, &message),
+    };
+    if let Err(message) =
+        utils::check_capability(&state.model_manager, &model, Capability::Embedding)
+    {
+        return error(StatusCode::BAD_REQUEST, &message);
+    }
+    if let Err(message) =
+        state
+            .collections
+            .create(&request_body.name, &model, request_body.similarity)
+    {
+        return error(StatusCode::CONFLICT, &message);
+    }
+    let info = state
+        .collections
+        .with(&request_body.name, |c| CollectionInfo::from(c))
+        .expect("collection was just created");
+    (StatusCode::CREATED, Json(info)).into_response()
+}
+
+pub async fn delete_collection(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match state.collections.delete(&name) {
+        Ok(true) => (
+            StatusCode::OK,
+            Json(StatusMessage {
+                message: format!("Collection {} deleted", name),
+            }),
+        )
+            .into_response(),
+        Ok(false) => error(StatusCode::NOT_FOUND, "Collection not found"),
+        Err(message) => error(StatusCode::INTERNAL_SERVER_ERROR, &message),
+    }
+}
+
+pub async fn upsert_documents(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(request_body): Json<UpsertDocumentsRequest>,
+) -> impl IntoResponse {
+    let Some(model) = state.collections.with(&name, |c| c.model.clone()) else {
+        return error(StatusCode::NOT_FOUND, "Collection not found");
+    };
+    let texts: Vec<String> = request_body
+        .documents
+        .iter()
+        .map(|d| d.text.clone())
+        .collect();
+    let embeddings = match embed(
+        &state.model_manager,
+        &state.embedding_cache,
+        &model,
+        texts,
+        InputType::SearchDocument,
+    )
+    .await
+    {
+        Ok(embeddings) => embeddings,
+        Err((status, message)) => return error(status, &message),
+    };
+    let total_tokens = embeddings.iter().map(|e| e.n_tokens).sum();
+    let documents: Vec<StoredDocument> = request_body
+        .documents
+        .into_iter()
+        .zip(embeddings)
+        .map(|(document, embedding)| StoredDocument {
+            id: document
+                .id
+                .unwrap_or_else(|| default_document_id(&document.text)),
+            text: document.text,
+            metadata: document.metadata,
+            embedding: embedding.embedding,
+        })
+        .collect();
+    let ids = documents.iter().map(|d| d.id.clone()).collect();
+    if let Err(message) = state.collections.upsert(&name, documents) {
+        return error(StatusCode::BAD_REQUEST, &message);
+    }
+    (
+        StatusCode::OK,
+        Json(UpsertDocumentsResponse {
+            meta: ServerMetadata::new(),
+            ids,
+            total_tokens,
+        }),
+    )
+        .into_response()
+}
+
+pub async fn delete_document(
+    State(state): State<AppState>,
+    Path((name, id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    match state.collections.delete_document(&name, &id) {
+        Ok(true) => (
+            StatusCode::OK,
+            Json(StatusMessage {
+                message: format!("Document {} deleted", id),
+            }),
+        )
+            .into_response(),
+        Ok(false) => error(StatusCode::NOT_FOUND, "Document not found"),
+        Err(message) => error(StatusCode::NOT_FOUND, &message),
+    }
+}
+
+pub async fn query_collection(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(request_body): Json<QueryCollectionRequest>,
+) -> impl IntoResponse {
+    let top_k = request_body.top_k.unwrap_or(10);
+    let filter = request_body.filter.as_ref();
+    let results = match (request_body.text.as_ref(), request_body.vector.as_ref()) {
+        (Some(text), None) => {
+            retrieve(
+                &state.model_manager,
+                &state.embedding_cache,
+                &state.collections,
+                &name,
+                text,
+                top_k,
+                filter,
+            )
+            .await
+        }
+        (None, Some(vector)) => search(&state.collections, &name, vector, top_k, filter),
+        _ => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "exactly one of text and vector must be set",
+            )
+        }
+    };
+    match results {
+        Ok(results) => (
+            StatusCode::OK,
+            Json(QueryCollectionResponse {
+                meta: ServerMetadata::new(),
+                results,
+            }),
+        )
+            .into_response(),
+        Err((status, message)) => error(status, &message),
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// How query vectors are compared against stored ones
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Similarity {
+    #[default]
+    Cosine,
+    Dot,
+}
+
+impl Similarity {
+    pub fn score(&self, a: &[f32], b: &[f32]) -> f32 {
+        let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        match self {
+            Similarity::Dot => dot,
+            Similarity::Cosine => {
+                let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+                if norm_a == 0.0 || norm_b == 0.0 {
+                    0.0
+                } else {
+                    dot / (norm_a * norm_b)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredDocument {
+    pub id: String,
+    pub text: String,
+    pub metadata: Map<String, Value>,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Collection {
+    pub name: String,
+    pub model: String,
+    pub similarity: Similarity,
+    pub dimensions: Option<usize>,
+    pub documents: BTreeMap<String, StoredDocument>,
+}
+
+/// A document that matched a query
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScoredDocument {
+    pub id: String,
+    pub score: f32,
+    pub text: String,
+    pub metadata: Map<String, Value>,
+}
+
+/// Check a document's metadata against a filter
+/// Every key in the filter has to match, an array value matches any of its items
+pub fn matches_filter(metadata: &Map<String, Value>, filter: &Map<String, Value>) -> bool {
+    filter
+        .iter()
+        .all(|(key, expected)| match metadata.get(key) {
+            None => false,
+            Some(actual) => match expected {
+                Value::Array(options) if !actual.is_array() => options.contains(actual),
+                _ => actual == expected,
+            },
+        })
+}
+
+impl Collection {
+    /// The `top_k` closest documents to `vector` that pass `filter`, best first
+    pub fn query(
+        &self,
+        vector: &[f32],
+        top_k: usize,
+        filter: Option<&Map<String, Value>>,
+    ) -> Vec<ScoredDocument> {
+        let mut scored: Vec<(f32, &StoredDocument)> = self
+            .documents
+            .values()
+            .filter(|doc| match filter {
+                Some(filter) => matches_filter(&doc.metadata, filter),
+                None => true,
+            })
+            .map(|doc| (self.similarity.score(vector, &doc.embedding), doc))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(top_k)
+            .map(|(score, doc)| ScoredDocument {
+                id: doc.id.clone(),
+                score,
+                text: doc.text.clone(),
+                metadata: doc.metadata.clone(),
+            })
+            .collect()
+    }
+}
+
+/// All collections, kept in memory and written to a single JSON file on every change
+pub struct CollectionStore {
+    path: PathBuf,
+    collections: RwLock<HashMap<String, Collection>>,
+}
+
+impl CollectionStore {
+    /// Load the store from `path`, starting empty if the file doesn't exist yet
+    pub fn open(path: &str) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        let collections = if path.exists() {
+            let data = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+            serde_json::from_str(&data).map_err(|e| e.to_string())?
+        } else {
+            HashMap::new()
+        };
+        Ok(CollectionStore {
+            path,
+            collections: RwLock::new(collections),
+        })
+    }
+
+    fn save(&self, collections: &HashMap<String, Collection>) -> Result<(), String> {
+        let data = serde_json::to_string(collections).map_err(|e| e.to_string())?;
+        // Write to a temp file first so a crash mid write can't eat the store
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, data).map_err(|e| e.to_string())?;
+        std::fs::rename(&tmp, &self.path).map_err(|e| e.to_string())
+    }
+
+    pub fn list(&self) -> Vec<Collection> {
+        self.collections.read().unwrap().values().cloned().collect()
+    }
+
+    /// Run `f` against a collection without copying it
+    pub fn with<T>(&self, name: &str, f: impl FnOnce(&Collection) -> T) -> Option<T> {
+        self.collections.read().unwrap().get(name).map(f)
+    }
+
+    pub fn create(&self, name: &str, model: &str, similarity: Similarity) -> Result<(), String> {
+        let mut collections = self.collections.write().unwrap();
+        if collections.contains_key(name) {
+            return Err(format!("Collection {} already exists", name));
+        }
+        collections.insert(
+            name.to_string(),
+            Collection {
+                name: name.to_string(),
+                model: model.to_string(),
+                similarity,
+                dimensions: None,
+                documents: BTreeMap::new(),
+            },
+        );
+        self.save(&collections)
+    }
+
+    /// Returns false if there was no such collection
+    pub fn delete(&self, name: &str) -> Result<bool, String> {
+        let mut collections = self.collections.write().unwrap();
+        if collections.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save(&collections).map(|_| true)
+    }
+
+    /// Insert or replace documents, they all need the collection's dimensions
+    /// Nothing is inserted unless every document has them
+    pub fn upsert(&self, name: &str, documents: Vec<StoredDocument>) -> Result<(), String> {
+        let mut collections = self.collections.write().unwrap();
+        let collection = collections
+            .get_mut(name)
+            .ok_or_else(|| format!("Collection {} not found", name))?;
+        // An empty collection takes its dimensions from the first document
+        let Some(dimensions) = collection
+            .dimensions
+            .or_else(|| documents.first().map(|d| d.embedding.len()))
+        else {
+            return Ok(());
+        };
+        if let Some(document) = documents.iter().find(|d| d.embedding.len() != dimensions) {
+            return Err(format!(
+                "Document {} has {} dimensions, the collection has {}",
+                document.id,
+                document.embedding.len(),
+                dimensions
+            ));
+        }
+        collection.dimensions = Some(dimensions);
+        for document in documents {
+            collection.documents.insert(document.id.clone(), document);
+        }
+        self.save(&collections)
+    }
+
+    /// Returns false if the document wasn't there
+    pub fn delete_document(&self, name: &str, id: &str) -> Result<bool, String> {
+        let mut collections = self.collections.write().unwrap();
+        let collection = collections
+            .get_mut(name)
+            .ok_or_else(|| format!("Collection {} not found", name))?;
+        if collection.documents.remove(id).is_none() {
+            return Ok(false);
+        }
+        self.save(&collections).map(|_| true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(id: &str, embedding: Vec<f32>) -> StoredDocument {
+        StoredDocument {
+            id: id.to_string(),
+            text: id.to_string(),
+            metadata: Map::new(),
+            embedding,
+        }
+    }
+
+    #[test]
+    fn upsert_is_all_or_nothing() {
+        let path = std::env::temp_dir().join(format!("collections-{}.json", std::process::id()));
+        let store = CollectionStore::open(path.to_str().unwrap()).unwrap();
+        store.create("docs", "model", Similarity::Cosine).unwrap();
+        let mixed = vec![document("a", vec![1.0, 0.0]), document("b", vec![1.0])];
+        assert!(store.upsert("docs", mixed).is_err());
+        let (dimensions, count) = store
+            .with("docs", |c| (c.dimensions, c.documents.len()))
+            .unwrap();
+        assert_eq!((dimensions, count), (None, 0));
+
+        store
+            .upsert("docs", vec![document("a", vec![1.0, 0.0])])
+            .unwrap();
+        assert!(store
+            .upsert("docs", vec![document("c", vec![0.0, 1.0, 0.0])])
+            .is_err());
+        assert_eq!(store.with("docs", |c| c.dimensions).unwrap(), Some(2));
+        let _ = std::fs::remove_file(path);
+    }
+}
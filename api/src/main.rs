@@ -1,35 +1,122 @@
+mod admin_routes;
+mod collection_routes;
+mod collections;
 mod prompt;
+mod reload;
 mod routes;
 mod tools;
 mod types;
 mod utils;
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use tower_http::cors::CorsLayer;
 
-use crate::types::Config;
+use crate::collections::CollectionStore;
+use crate::types::{AppState, Config};
 use axum::{
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post},
     Router,
 };
-use shurbai::load_models;
+use shurbai::{embedding_cache::EmbeddingCache, load_models};
 
 #[tokio::main]
 async fn main() {
     // read the config file
-    let config_data = std::fs::read_to_string("./config.json").expect("failed to read config file");
+    let config_path = PathBuf::from("./config.json");
+    let config_data = std::fs::read_to_string(&config_path).expect("failed to read config file");
     let config: Config = serde_json::from_str(&config_data)
         .expect("failed to parse and/or assign default Json and config");
     println!("Loaded config.json");
-    let model_manager = Arc::new(load_models(config.models).expect("failed to load models"));
+    let memory_budget = config.memory_budget_mb.map(|mb| mb * 1_000_000);
+    let routing = config.routing();
+    let model_manager = Arc::new(
+        load_models(config.models, memory_budget).expect("failed to set up the model manager"),
+    );
+    model_manager.set_routing(routing);
+    // Unload models that have sat idle past their keep_alive
+    let reaper_manager = model_manager.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            for name in reaper_manager.unload_idle() {
+                println!("unloaded idle model {}", name);
+            }
+        }
+    });
+    reload::watch(model_manager.clone(), config_path);
+    let collections_path = config
+        .collections_path
+        .unwrap_or_else(|| "./collections.json".to_string());
+    let collections =
+        Arc::new(CollectionStore::open(&collections_path).expect("failed to load collections"));
+    let cache_config = config.embedding_cache.unwrap_or_default();
+    let embedding_cache = Arc::new(
+        EmbeddingCache::new(
+            cache_config.capacity.unwrap_or(4096),
+            cache_config.path.map(PathBuf::from),
+            cache_config.disk_capacity.unwrap_or(65536),
+        )
+        .expect("failed to set up the embedding cache"),
+    );
+    let state = AppState {
+        model_manager,
+        collections,
+        embedding_cache,
+        admin_token: config.admin_token,
+    };
+    let admin = Router::new()
+        .route(
+            "/admin/models",
+            get(admin_routes::list_models).post(admin_routes::load_model),
+        )
+        .route(
+            "/admin/models/:name",
+            get(admin_routes::get_model)
+                .patch(admin_routes::update_model)
+                .delete(admin_routes::delete_model),
+        )
+        .route(
+            "/admin/models/:name/unload",
+            post(admin_routes::unload_model),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            admin_routes::require_admin,
+        ));
     // build our application with a single route
     let app = Router::new()
         .route("/models", get(routes::list_models))
+        .route("/models/:name", get(routes::model_details))
         .route("/generate", post(routes::generate))
         .route("/generate/chat", post(routes::chat_generate))
         .route("/embeddings", post(routes::generate_embeding))
+        .route("/embeddings/cache", get(routes::embedding_cache_stats))
+        .route("/rerank", post(routes::rerank))
+        .route(
+            "/collections",
+            get(collection_routes::list_collections).post(collection_routes::create_collection),
+        )
+        .route(
+            "/collections/:name",
+            delete(collection_routes::delete_collection),
+        )
+        .route(
+            "/collections/:name/documents",
+            post(collection_routes::upsert_documents),
+        )
+        .route(
+            "/collections/:name/documents/:id",
+            delete(collection_routes::delete_document),
+        )
+        .route(
+            "/collections/:name/query",
+            post(collection_routes::query_collection),
+        )
+        .merge(admin)
         .layer(CorsLayer::permissive()) // add CORS headers to each response, this is just to get stage one working
-        .with_state(model_manager);
+        .with_state(state);
 
     // run our app with hyper, listening globally on port 8080
     let address = "0.0.0.0:8080";
@@ -1,9 +1,11 @@
+use minijinja::{context, Environment, ErrorKind};
 use serde::Serialize;
-use shurbai::types::ChatTemplate;
+use shurbai::types::{ChatTemplate, JinjaTemplate, ModelState, PromptTemplate};
 use std::io::{Error, Result};
 use tinytemplate::TinyTemplate;
 
-use crate::types::Message;
+use crate::tools;
+use crate::types::{Citation, Message, ToolDefinition};
 
 #[derive(Serialize)]
 struct TemplateContext {
@@ -24,6 +26,74 @@ const TOOL_TEMPLATE_JSON: &str = r#"{
     }
 }"#;
 
+/// The default context block for RAG chats, rendered into a system message
+pub const RAG_TEMPLATE: &str = "Answer the question using the passages below. \
+Cite the passages you use by their number, like [1]. \
+If they don't contain the answer, say so.
+
+{{ for passage in passages }}[{passage.index}] {passage.text}
+{{ endfor }}";
+
+#[derive(Serialize)]
+struct RagTemplateContext<'a> {
+    question: &'a str,
+    passages: &'a [Citation],
+}
+
+/// Render the retrieved passages with `template`
+/// # Errors
+/// If the template doesn't parse or references something that isn't there
+pub fn render_rag_context(
+    template: &str,
+    question: &str,
+    passages: &[Citation],
+) -> std::result::Result<String, String> {
+    let mut tt = TinyTemplate::new();
+    tt.add_template("rag", template)
+        .map_err(|e| format!("bad rag template: {}", e))?;
+    tt.set_default_formatter(&tinytemplate::format_unescaped);
+    tt.render("rag", &RagTemplateContext { question, passages })
+        .map_err(|e| format!("bad rag template: {}", e))
+}
+
+/// Render the chat prompt with the retrieved passages added as a system message before the
+/// user message at `last_user`. The lowest ranked passages are dropped until the prompt leaves
+/// `reserved` tokens of the context free for the answer
+/// # Returns
+/// The prompt and the passages that made it in
+/// # Errors
+/// If a template fails to render, or the prompt doesn't fit even without any passages
+#[allow(clippy::too_many_arguments)]
+pub fn render_rag_chat_prompt(
+    model: &ModelState,
+    messages: &[Message],
+    last_user: usize,
+    rag_template: &str,
+    mut passages: Vec<Citation>,
+    template: &PromptTemplate,
+    tools: &[ToolDefinition],
+    reserved: usize,
+) -> std::result::Result<(String, Vec<Citation>), String> {
+    let budget = model.context_size().saturating_sub(reserved);
+    let question = &messages[last_user].content;
+    loop {
+        let context = render_rag_context(rag_template, question, &passages)?;
+        let mut with_context = messages.to_vec();
+        with_context.insert(last_user, Message::new("system", &context));
+        let prompt = render_chat_prompt(&with_context, template, tools)?;
+        let n_tokens = model.count_tokens(&prompt).map_err(|e| e.to_string())?;
+        if n_tokens <= budget {
+            return Ok((prompt, passages));
+        }
+        if passages.pop().is_none() {
+            return Err(format!(
+                "The prompt takes {} tokens, only {} fit in the context once {} are kept for the answer",
+                n_tokens, budget, reserved
+            ));
+        }
+    }
+}
+
 pub fn generate_chat_prompt(messages: &Vec<Message>, template: &ChatTemplate) -> Result<String> {
     let assistant_template = template.assistant_template.clone();
     let mut tt = TinyTemplate::new();
@@ -83,3 +153,92 @@ pub fn generate_chat_prompt(messages: &Vec<Message>, template: &ChatTemplate) ->
     prompt.push_str(&template.assistant_prompt_template);
     Ok(prompt)
 }
+
+/// Turn our messages into the ones Hugging Face templates expect
+/// Tool definitions go to the template's `tools` when it has them, otherwise into the
+/// system prompt, and tool results take the `tool` role
+fn template_messages(messages: &[Message], template_has_tools: bool) -> Vec<Message> {
+    let mut tool_defs = Vec::new();
+    let mut converted = Vec::with_capacity(messages.len());
+    for message in messages {
+        match message.role.as_str() {
+            "tool" => tool_defs.push(message.content.as_str()),
+            "tool_response" => converted.push(Message::new("tool", &message.content)),
+            _ => converted.push(message.clone()),
+        }
+    }
+    if template_has_tools || tool_defs.is_empty() {
+        return converted;
+    }
+    let defs = tool_defs.join("\n");
+    match converted.first_mut() {
+        Some(first) if first.role == "system" => {
+            first.content = format!("{}\n\n{}", first.content, defs);
+        }
+        _ => converted.insert(0, Message::new("system", &defs)),
+    }
+    converted
+}
+
+/// Render a Jinja chat template the way Hugging Face's `apply_chat_template` does
+/// # Arguments
+/// * `messages` - The chat so far
+/// * `template` - The template and the special tokens it uses
+/// * `tools` - The tools the model can call, passed to the template as `tools`
+/// # Errors
+/// If the template doesn't parse or raises an exception for these messages
+pub fn render_jinja(
+    messages: &[Message],
+    template: &JinjaTemplate,
+    tools: &[ToolDefinition],
+) -> std::result::Result<String, String> {
+    let mut env = Environment::new();
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    // Python string and dict methods like .strip() and .items()
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function(
+        "raise_exception",
+        |message: String| -> std::result::Result<String, minijinja::Error> {
+            Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+        },
+    );
+    env.add_function("strftime_now", |format: String| {
+        chrono::Local::now().format(&format).to_string()
+    });
+    let tmpl = env
+        .template_from_str(&template.source)
+        .map_err(|e| format!("bad chat template: {}", e))?;
+    let template_has_tools = tmpl.undeclared_variables(false).contains("tools");
+    let tools = (!tools.is_empty()).then(|| tools::template_tools(tools));
+    let prompt = tmpl
+        .render(context! {
+            messages => template_messages(messages, template_has_tools),
+            tools => tools,
+            bos_token => template.bos_token,
+            eos_token => template.eos_token,
+            add_generation_prompt => true,
+        })
+        .map_err(|e| format!("failed to render the chat template: {}", e))?;
+    // The tokenizer adds BOS itself, don't end up with two
+    match prompt.strip_prefix(template.bos_token.as_str()) {
+        Some(rest) if !template.bos_token.is_empty() => Ok(rest.to_string()),
+        _ => Ok(prompt),
+    }
+}
+
+/// Build the prompt for a chat with whichever kind of template the model has
+/// # Errors
+/// If the messages can't be rendered with the template
+pub fn render_chat_prompt(
+    messages: &Vec<Message>,
+    template: &PromptTemplate,
+    tools: &[ToolDefinition],
+) -> std::result::Result<String, String> {
+    match template {
+        PromptTemplate::Roles(template) => {
+            generate_chat_prompt(messages, template).map_err(|e| e.to_string())
+        }
+        PromptTemplate::Jinja(template) => render_jinja(messages, template, tools),
+    }
+}
@@ -0,0 +1,84 @@
+//! Picks up model changes in config.json without a restart, either when the file changes
+//! or when the server gets a SIGHUP
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use shurbai::types::ModelManager;
+
+use crate::types::Config;
+
+/// How often the config file's modification time is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Read the config again and hand the models to the manager
+/// A config that doesn't parse is reported and ignored, the current models keep serving
+fn reload(model_manager: &ModelManager, path: &Path) {
+    let config: Config = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_str(&data).map_err(|e| e.to_string()))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            println!("not reloading {}: {}", path.display(), e);
+            return;
+        }
+    };
+    model_manager.set_routing(config.routing());
+    let summary = model_manager.reload(config.models);
+    println!(
+        "reloaded {} (added: {:?}, removed: {:?}, changed: {:?})",
+        path.display(),
+        summary.added,
+        summary.removed,
+        summary.changed
+    );
+}
+
+/// `reload` on the blocking pool, it reads the config and the GGUF header of every new or
+/// changed model
+async fn reload_in_background(model_manager: &Arc<ModelManager>, path: &Path) {
+    let model_manager = model_manager.clone();
+    let path = path.to_path_buf();
+    if let Err(e) = tokio::task::spawn_blocking(move || reload(&model_manager, &path)).await {
+        println!("reload failed: {}", e);
+    }
+}
+
+/// Start watching `path` in the background
+pub fn watch(model_manager: Arc<ModelManager>, path: PathBuf) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("failed to listen for SIGHUP");
+        let mut last_modified = modified(&path);
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = hangup.recv() => {
+                    println!("got SIGHUP");
+                    last_modified = modified(&path);
+                    reload_in_background(&model_manager, &path).await;
+                    continue;
+                }
+            }
+            #[cfg(not(unix))]
+            interval.tick().await;
+
+            let current = modified(&path);
+            if current != last_modified {
+                last_modified = current;
+                reload_in_background(&model_manager, &path).await;
+            }
+        }
+    });
+}
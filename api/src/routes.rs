@@ -1,60 +1,95 @@
 use std::sync::{Arc, Mutex};
 
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
 use axum_streams::StreamBodyAs;
 use shurbai::{
-    embeddings::generate_embeddings,
+    embedding_cache::EmbeddingCache,
+    embeddings::truncate_embedding,
+    grammar::validate_grammar,
     pretty_generate,
-    types::{LlamaResult, ModelManager},
+    types::{
+        Capability, EmbeddingNormalization, EmbeddingOptions, GrammarConstraint, LlamaResult,
+        ModelManager,
+    },
 };
 use tokio::task;
 use tokio_stream::wrappers::ReceiverStream;
 
 use crate::{
+    collection_routes,
+    collections::CollectionStore,
     get_model, prompt, tools,
     types::{
-        ChatGenerateCall, ChatGenerateResponse, ChatGenerateResponseChuck, EmbeddingsRequest,
-        EmbeddingsResponse, ErrorResponse, GenerateCall, GenerateResponse, GeneratreResponseChuck,
-        ListModelsResponse, Message, ModelListObject, ServerMetadata, XmlState,
+        ChatGenerateCall, ChatGenerateResponse, ChatGenerateResponseChuck, Citation,
+        EmbeddingCacheResponse, EmbeddingData, EmbeddingsInput, EmbeddingsRequest,
+        EmbeddingsResponse, EncodedChunk, ErrorResponse, GenerateCall, GenerateResponse,
+        GeneratreResponseChuck, ListModelsResponse, Message, ModelDetailsResponse, ModelListObject,
+        RerankRequest, RerankResponse, RerankResult, ServerMetadata, XmlState,
     },
-    utils::{self, has_model, process_xml_token},
+    utils::{self, process_xml_token},
 };
 
 pub async fn generate(
     State(model_manager): State<Arc<ModelManager>>,
     Json(request_body): Json<GenerateCall>,
 ) -> impl IntoResponse {
-    if !has_model(model_manager.as_ref(), &request_body.model) {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse::new("Model not found")),
-        )
-            .into_response();
+    let model_name = match utils::route_model(
+        &model_manager,
+        request_body.model.as_deref(),
+        &[Capability::Completion],
+    ) {
+        Ok(model_name) => model_name,
+        Err(message) => {
+            return (StatusCode::NOT_FOUND, Json(ErrorResponse::new(&message))).into_response()
+        }
+    };
+    if let Err(message) =
+        utils::check_capability(&model_manager, &model_name, Capability::Completion)
+    {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message))).into_response();
     }
+    let grammar = match utils::resolve_grammar(
+        request_body.grammar.as_ref(),
+        request_body.response_format.as_ref(),
+        request_body.format.as_ref(),
+        request_body.regex.as_ref(),
+    ) {
+        Ok(grammar) => grammar,
+        Err(message) => {
+            return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message))).into_response()
+        }
+    };
 
-    let max_tokens = request_body
-        .generate_params
-        .as_ref()
-        .map_or(512, |p| p.max_tokens.unwrap());
+    let (model_name, model_state) =
+        get_model!(model_manager, &model_name, &[Capability::Completion]);
+    let (sampling, max_tokens) =
+        utils::generation_options(&model_state, request_body.generate_params.as_ref());
+    if let Err(message) = model_state.lora_scales(request_body.lora_adapters.as_deref()) {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message))).into_response();
+    }
 
     if request_body.stream.unwrap_or(false) {
         let (tx, rx) = tokio::sync::mpsc::channel(100);
         task::spawn(async move {
-            let model_state = get_model!(&model_manager, &request_body.model); // I don't like this, but we need it for the threading
             let tx_arc = Arc::new(tokio::sync::Mutex::new(tx));
             pretty_generate(
-                model_state,
+                &model_state,
                 &model_manager.backend,
                 &request_body.prompt,
                 max_tokens,
-                &model_state.chat_template.stops,
+                &model_state.stops(),
                 Some(Box::new(move |s, is_last| {
                     utils::send_to_stream(
                         Arc::clone(&tx_arc),
                         &GeneratreResponseChuck {
                             meta: ServerMetadata::new(),
                             token_str: s,
-                            model: request_body.model.clone(),
+                            model: model_name.clone(),
                             halt_reason: if is_last {
                                 Some("End of stream".to_string())
                             } else {
@@ -63,7 +98,9 @@ pub async fn generate(
                         },
                     );
                 })),
-                Some(false),
+                grammar.as_ref(),
+                &sampling,
+                request_body.lora_adapters.as_deref(),
             )
             .expect("Failed to generate"); //TODO: At some point lets return the full info to the user
         });
@@ -71,66 +108,200 @@ pub async fn generate(
         return StreamBodyAs::json_nl(rx_stream).into_response();
     }
 
-    let model_state = get_model!(&model_manager, &request_body.model);
     let response = pretty_generate(
-        model_state,
+        &model_state,
         &model_manager.backend,
         &request_body.prompt,
         max_tokens,
-        &model_state.chat_template.stops,
+        &model_state.stops(),
         None,
-        Some(false),
+        grammar.as_ref(),
+        &sampling,
+        request_body.lora_adapters.as_deref(),
     )
     .expect("Failed to generate");
     println!("response {:?}", response.generated_tokens_data);
+    let text = response.generated_tokens_data.concat();
+    let parsed = match utils::parse_structured_output(request_body.response_format.as_ref(), &text)
+    {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ErrorResponse::new(&message)),
+            )
+                .into_response()
+        }
+    };
     let obj = GenerateResponse {
         meta: ServerMetadata::new(),
-        response: response.generated_tokens_data.concat(),
-        model: request_body.model.clone(),
+        response: text,
+        model: model_name.clone(),
         took: response.duration.as_nanos(),
         halt_reason: None,
+        parsed,
     };
     (StatusCode::OK, Json(obj)).into_response()
 }
 
 pub async fn chat_generate(
     State(model_manager): State<Arc<ModelManager>>,
+    State(collections): State<Arc<CollectionStore>>,
+    State(embedding_cache): State<Arc<EmbeddingCache>>,
     Json(request_body): Json<ChatGenerateCall>,
 ) -> impl IntoResponse {
-    if !has_model(&model_manager.as_ref(), &request_body.model) {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse::new("Model not found")),
-        )
-            .into_response();
+    let model_name = match utils::route_model(
+        &model_manager,
+        request_body.model.as_deref(),
+        &[Capability::Chat],
+    ) {
+        Ok(model_name) => model_name,
+        Err(message) => {
+            return (StatusCode::NOT_FOUND, Json(ErrorResponse::new(&message))).into_response()
+        }
+    };
+    if let Err(message) = utils::check_capability(&model_manager, &model_name, Capability::Chat) {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message))).into_response();
     }
+    let mut grammar = match utils::resolve_grammar(
+        request_body.grammar.as_ref(),
+        request_body.response_format.as_ref(),
+        request_body.format.as_ref(),
+        request_body.regex.as_ref(),
+    ) {
+        Ok(grammar) => grammar,
+        Err(message) => {
+            return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message))).into_response()
+        }
+    };
     let mut messages = request_body.messages.clone();
+    let tool_defs = request_body.tools.clone().unwrap_or_default();
     let has_tools = request_body.tools.is_some(); // Emoty tools should be handled client side
     if has_tools {
-        let defs_text = tools::build_tool_defs_text(request_body.tools.unwrap());
+        let defs_text = tools::build_tool_defs_text(&tool_defs);
         messages.insert(0, Message::new("tool", &defs_text));
     }
-    let model_state = get_model!(&model_manager, &request_body.model);
+    // Unless the caller brought their own constraint, make sure tool calls can't come out malformed
+    if grammar.is_none() && !tool_defs.is_empty() {
+        let tool_call_only = request_body.tool_call_only.unwrap_or(false);
+        grammar = match tools::build_tool_grammar(&tool_defs, tool_call_only)
+            .and_then(|g| validate_grammar(&g).map(|_| g).map_err(|e| e.to_string()))
+        {
+            Ok(g) => Some(GrammarConstraint::new(g)),
+            Err(message) => {
+                return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message)))
+                    .into_response()
+            }
+        };
+    }
+    // The passages and where they go, the prompt is rendered once the model is loaded
+    let mut rag_context = None;
+    if let Some(rag) = request_body.rag.as_ref() {
+        let Some(last_user) = messages.iter().rposition(|m| m.role == "user") else {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse::new(
+                    "rag needs a user message to search with",
+                )),
+            )
+                .into_response();
+        };
+        let question = messages[last_user].content.clone();
+        let passages = match collection_routes::retrieve(
+            &model_manager,
+            &embedding_cache,
+            &collections,
+            &rag.collection,
+            &question,
+            rag.top_k.unwrap_or(4),
+            rag.filter.as_ref(),
+        )
+        .await
+        {
+            Ok(passages) => passages,
+            Err((status, message)) => {
+                return (status, Json(ErrorResponse::new(&message))).into_response()
+            }
+        };
+        let retrieved: Vec<Citation> = passages
+            .into_iter()
+            .enumerate()
+            .map(|(i, doc)| Citation {
+                index: i + 1,
+                id: doc.id,
+                score: doc.score,
+                text: doc.text,
+                metadata: doc.metadata,
+            })
+            .collect();
+        let template = rag.template.as_deref().unwrap_or(prompt::RAG_TEMPLATE);
+        rag_context = Some((last_user, template, retrieved));
+    }
+    let (model_name, model_state) = get_model!(model_manager, &model_name, &[Capability::Chat]);
+    let (sampling, max_tokens) =
+        utils::generation_options(&model_state, request_body.generate_params.as_ref());
+    if let Err(message) = model_state.lora_scales(request_body.lora_adapters.as_deref()) {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message))).into_response();
+    }
 
-    let prompt = prompt::generate_chat_prompt(&messages, &model_state.chat_template)
-        .expect("Failed to generate prompt");
+    let Some(chat_template) = model_state.chat_template.as_ref() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse::new(&format!(
+                "Model {} has no chat_template and its GGUF doesn't embed one",
+                model_name
+            ))),
+        )
+            .into_response();
+    };
+    let rendered = match rag_context {
+        Some((last_user, rag_template, retrieved)) => prompt::render_rag_chat_prompt(
+            &model_state,
+            &messages,
+            last_user,
+            rag_template,
+            retrieved,
+            chat_template,
+            &tool_defs,
+            max_tokens.max(0) as usize,
+        )
+        .map(|(prompt, kept)| (prompt, Some(kept))),
+        None => prompt::render_chat_prompt(&messages, chat_template, &tool_defs)
+            .map(|prompt| (prompt, None)),
+    };
+    let (prompt, citations) = match rendered {
+        Ok(rendered) => rendered,
+        Err(message) => {
+            return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message))).into_response()
+        }
+    };
 
     if request_body.stream.unwrap_or(false) {
         let (tx, rx) = tokio::sync::mpsc::channel(100);
         let model_manager = model_manager.clone();
+        let model_state = model_state.clone();
         let prompt = prompt.clone();
+        let stream_tool_defs = tool_defs.clone();
         task::spawn(async move {
-            let model_name = request_body.model.clone();
-            let model_state = get_model!(&model_manager, &model_name); // I don't like this, but we need it for the threading
+            let model_name = model_name.clone();
+            if let Some(citations) = citations {
+                // Sent directly so it's guaranteed to arrive before the tokens
+                tx.send(ChatGenerateResponseChuck::new_citations(
+                    &model_name,
+                    citations,
+                ))
+                .await
+                .expect("Failed to stream");
+            }
             let tx_arc = Arc::new(tokio::sync::Mutex::new(tx));
             let tx_arc_ref = Arc::clone(&tx_arc);
             let xml_state = Arc::new(Mutex::new(XmlState::new()));
             let r = pretty_generate(
-                model_state,
+                &model_state,
                 &model_manager.backend,
                 &prompt,
-                512,
-                &model_state.chat_template.stops,
+                max_tokens,
+                &model_state.stops(),
                 Some(Box::new(move |s, is_last| {
                     let tx_arc = Arc::clone(&tx_arc_ref);
                     let mut xml_state = xml_state.lock().unwrap();
@@ -140,17 +311,21 @@ pub async fn chat_generate(
                             // Pull out tool calls
                             let ts = t.unwrap();
                             print!("ts {:?}", ts);
-                            let tool_call = tools::parse_tool_call(&ts);
-                            let tool_calls = if tool_call.is_some() {
-                                vec![tool_call.unwrap()]
-                            } else {
-                                Vec::new()
+                            let block = match tools::parse_tool_call(&ts, &stream_tool_defs) {
+                                Ok(tool_call) => ChatGenerateResponseChuck::new_tool_call(
+                                    &model_name,
+                                    vec![tool_call],
+                                ),
+                                Err(message) => {
+                                    // Streamed calls get the same checks as the non streaming
+                                    // path, but the response is already under way so a rejected
+                                    // call goes back as plain text instead of a 422
+                                    println!("rejected tool call: {}", message);
+                                    ChatGenerateResponseChuck::new_token(&model_name, &ts)
+                                }
                             };
                             // send them
-                            utils::send_to_stream(
-                                Arc::clone(&tx_arc),
-                                &ChatGenerateResponseChuck::new_tool_call(&model_name, tool_calls),
-                            );
+                            utils::send_to_stream(Arc::clone(&tx_arc), &block);
                         }
                     }
                     if !xml_state.halt_output && &s != ">" {
@@ -163,7 +338,9 @@ pub async fn chat_generate(
                         utils::send_to_stream(Arc::clone(&tx_arc), &block);
                     }
                 })),
-                Some(false),
+                grammar.as_ref(),
+                &sampling,
+                request_body.lora_adapters.as_deref(),
             )
             .expect("Failed to generate"); //TODO: At some point lets return the full info to the user
         });
@@ -172,61 +349,303 @@ pub async fn chat_generate(
     }
 
     let response = pretty_generate(
-        model_state,
+        &model_state,
         &model_manager.backend,
         &prompt,
-        512,
-        &model_state.chat_template.stops,
+        max_tokens,
+        &model_state.stops(),
         None,
-        Some(false),
+        grammar.as_ref(),
+        &sampling,
+        request_body.lora_adapters.as_deref(),
     )
     .expect("Failed to generate");
 
+    let mut text = response.generated_tokens_data.concat();
+    let mut tool_calls = None;
+    if has_tools {
+        let (remaining, calls) = tools::extract_tool_calls(&text, &tool_defs);
+        let mut parsed_calls = Vec::new();
+        for call in calls {
+            match call {
+                Ok(call) => parsed_calls.push(call),
+                Err(message) => {
+                    return (
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        Json(ErrorResponse::new(&message)),
+                    )
+                        .into_response()
+                }
+            }
+        }
+        text = remaining;
+        tool_calls = Some(parsed_calls);
+    }
+    let parsed = match utils::parse_structured_output(request_body.response_format.as_ref(), &text)
+    {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ErrorResponse::new(&message)),
+            )
+                .into_response()
+        }
+    };
+    let citations = citations.map(|c| utils::cited_passages(&text, c));
     let obj = ChatGenerateResponse {
         meta: ServerMetadata::new(),
-        response: response.generated_tokens_data.concat(),
-        model: request_body.model.clone(),
+        response: text,
+        model: model_name.clone(),
         took: response.duration.as_nanos(),
-        tool_calls: None, //TODO: Need to re do the parsing here
+        tool_calls,
         halt_reason: None,
+        parsed,
+        citations,
     };
     return (StatusCode::OK, Json(obj)).into_response();
 }
 
 pub async fn list_models(State(model_manager): State<Arc<ModelManager>>) -> impl IntoResponse {
     let models = model_manager
-        .models
-        .iter()
-        .map(|(name, _model)| ModelListObject {
-            name: name.to_string(),
+        .status()
+        .into_iter()
+        .map(|model| ModelListObject {
+            name: model.name,
+            aliases: model.aliases,
             type_str: "model".to_string(),
+            capabilities: model.capabilities,
+            loaded: model.loaded,
         })
         .collect();
     let r = ListModelsResponse {
         meta: ServerMetadata::new(),
+        default_model: model_manager.route(None, &[]),
         models,
     };
     (StatusCode::OK, Json(r))
 }
 
-pub async fn generate_embeding(
+pub async fn model_details(
     State(model_manager): State<Arc<ModelManager>>,
-    Json(request_body): Json<EmbeddingsRequest>,
+    Path(name): Path<String>,
 ) -> impl IntoResponse {
-    if !has_model(&model_manager.as_ref(), &request_body.model) {
+    let (Some(status), Some(definition), Some(metadata)) = (
+        model_manager.model_status(&name),
+        model_manager.definition(&name),
+        model_manager.metadata(&name),
+    ) else {
         return (
             StatusCode::NOT_FOUND,
             Json(ErrorResponse::new("Model not found")),
         )
             .into_response();
+    };
+    let r = ModelDetailsResponse {
+        meta: ServerMetadata::new(),
+        file_size: std::fs::metadata(&definition.path).map(|m| m.len()).ok(),
+        path: definition.path,
+        name: status.name,
+        aliases: status.aliases,
+        loaded: status.loaded,
+        capabilities: status.capabilities,
+        estimated_bytes: status.estimated_bytes,
+        architecture: metadata.architecture().map(str::to_string),
+        parameter_count: metadata.parameter_count,
+        quantization: metadata.quantization(),
+        context_length: metadata.context_length(),
+        vocab_size: metadata.vocab_size(),
+        embedding_length: metadata
+            .arch_value("embedding_length")
+            .and_then(|v| v.as_u64()),
+        chat_template: metadata.chat_template().map(str::to_string),
+        special_tokens: metadata.special_tokens.clone(),
+        gguf_version: metadata.version,
+        config: definition.config.effective(),
+        lora_adapters: definition.lora_adapters,
+    };
+    (StatusCode::OK, Json(r)).into_response()
+}
+
+pub async fn generate_embeding(
+    State(model_manager): State<Arc<ModelManager>>,
+    State(embedding_cache): State<Arc<EmbeddingCache>>,
+    Json(request_body): Json<EmbeddingsRequest>,
+) -> impl IntoResponse {
+    if request_body.dimensions == Some(0) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse::new("dimensions must be at least 1")),
+        )
+            .into_response();
+    }
+    let model_name = match utils::route_model(
+        &model_manager,
+        request_body.model.as_deref(),
+        &[Capability::Embedding],
+    ) {
+        Ok(model_name) => model_name,
+        Err(message) => {
+            return (StatusCode::NOT_FOUND, Json(ErrorResponse::new(&message))).into_response()
+        }
+    };
+    if let Err(message) =
+        utils::check_capability(&model_manager, &model_name, Capability::Embedding)
+    {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message))).into_response();
     }
-    let model_state = get_model!(&model_manager, &request_body.model);
-    let response = generate_embeddings(model_state, &model_manager.backend, &request_body.prompt)
-        .expect("Failed to generate");
+    let (model_name, model_state) =
+        get_model!(model_manager, &model_name, &[Capability::Embedding]);
+    let single = matches!(request_body.prompt, EmbeddingsInput::Single(_));
+    let prompts = request_body.prompt.into_vec();
+    let options = EmbeddingOptions {
+        pooling: request_body.pooling,
+        normalization: request_body.normalization,
+        chunking: request_body.chunking.clone(),
+        input_type: request_body.input_type,
+    };
+    let embedded = utils::blocking(model_manager.clone(), {
+        let model_name = model_name.clone();
+        let model_state = model_state.clone();
+        move |model_manager| {
+            embedding_cache.embed(
+                &model_name,
+                &model_state,
+                &model_manager.backend,
+                &prompts,
+                &options,
+            )
+        }
+    })
+    .await;
+    let response = match embedded {
+        Ok(response) => response,
+        Err(e) => {
+            return (
+                utils::embedding_error_status(&e),
+                Json(ErrorResponse::new(&e.to_string())),
+            )
+                .into_response()
+        }
+    };
+    // Truncated vectors only stay unit length if we normalize them again
+    let normalise = request_body
+        .normalization
+        .or(model_state.config.embedding_normalization)
+        .unwrap_or(EmbeddingNormalization::L2)
+        == EmbeddingNormalization::L2;
+    let encoding = request_body.encoding_format.unwrap_or_default();
+    let finish = |embedding: &[f32]| match request_body.dimensions {
+        Some(dimensions) => utils::encode_embedding(
+            &truncate_embedding(embedding, dimensions, normalise),
+            encoding,
+        ),
+        None => utils::encode_embedding(embedding, encoding),
+    };
+    let data: Vec<EmbeddingData> = response
+        .into_iter()
+        .enumerate()
+        .map(|(index, r)| EmbeddingData {
+            index,
+            embedding: (!r.embedding.is_empty()).then(|| finish(&r.embedding)),
+            token_embeddings: r.token_embeddings.iter().map(|e| finish(e)).collect(),
+            chunks: r
+                .chunks
+                .iter()
+                .map(|c| EncodedChunk {
+                    start: c.start,
+                    end: c.end,
+                    embedding: finish(&c.embedding),
+                })
+                .collect(),
+            tokens: r.n_tokens,
+        })
+        .collect();
     let obj = EmbeddingsResponse {
         meta: ServerMetadata::new(),
-        embeddings: response,
-        model: request_body.model.clone(),
+        embeddings: if single {
+            data.first().and_then(|d| d.embedding.clone())
+        } else {
+            None
+        },
+        total_tokens: data.iter().map(|d| d.tokens).sum(),
+        data,
+        model: model_name.clone(),
+    };
+    (StatusCode::OK, Json(obj)).into_response()
+}
+
+pub async fn embedding_cache_stats(
+    State(embedding_cache): State<Arc<EmbeddingCache>>,
+) -> impl IntoResponse {
+    let r = EmbeddingCacheResponse {
+        meta: ServerMetadata::new(),
+        stats: embedding_cache.stats(),
+    };
+    (StatusCode::OK, Json(r))
+}
+
+pub async fn rerank(
+    State(model_manager): State<Arc<ModelManager>>,
+    Json(request_body): Json<RerankRequest>,
+) -> impl IntoResponse {
+    let model_name = match utils::route_model(
+        &model_manager,
+        request_body.model.as_deref(),
+        &[Capability::Embedding, Capability::Rerank],
+    ) {
+        Ok(model_name) => model_name,
+        Err(message) => {
+            return (StatusCode::NOT_FOUND, Json(ErrorResponse::new(&message))).into_response()
+        }
+    };
+    // Embedding models can stand in for a reranker by comparing vectors
+    if let Err(message) =
+        utils::check_capability(&model_manager, &model_name, Capability::Embedding)
+            .or_else(|_| utils::check_capability(&model_manager, &model_name, Capability::Rerank))
+    {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(&message))).into_response();
+    }
+    let (model_name, model_state) = get_model!(
+        model_manager,
+        &model_name,
+        &[Capability::Embedding, Capability::Rerank]
+    );
+    let scored = utils::blocking(model_manager.clone(), {
+        let query = request_body.query.clone();
+        let documents = request_body.documents.clone();
+        move |model_manager| {
+            shurbai::rerank::rerank(&model_state, &model_manager.backend, &query, &documents)
+        }
+    })
+    .await;
+    let (scores, method) = match scored {
+        Ok(scored) => scored,
+        Err(e) => {
+            return (
+                utils::embedding_error_status(&e),
+                Json(ErrorResponse::new(&e.to_string())),
+            )
+                .into_response()
+        }
+    };
+    let return_documents = request_body.return_documents.unwrap_or(false);
+    let mut results: Vec<RerankResult> = scores
+        .into_iter()
+        .enumerate()
+        .map(|(index, relevance_score)| RerankResult {
+            index,
+            relevance_score,
+            document: return_documents.then(|| request_body.documents[index].clone()),
+        })
+        .collect();
+    results.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+    results.truncate(request_body.top_n.unwrap_or(results.len()));
+    let obj = RerankResponse {
+        meta: ServerMetadata::new(),
+        model: model_name.clone(),
+        method,
+        results,
     };
     (StatusCode::OK, Json(obj)).into_response()
 }
@@ -1,9 +1,279 @@
+use serde_json::{json, Map, Value};
+use shurbai::json_schema::{self, schema_rules};
+
 use crate::types::{ToolCall, ToolDefinition};
 
-pub fn build_tool_defs_text(tool_calls: Vec<ToolDefinition>) -> String {
-    serde_json::to_string(&tool_calls).unwrap() // TODO: make it plane text
+pub fn build_tool_defs_text(tool_calls: &[ToolDefinition]) -> String {
+    serde_json::to_string(tool_calls).unwrap() // TODO: make it plane text
+}
+
+/// Map the loose `data_type` names people put in tool definitions onto JSON Schema
+fn argument_schema(data_type: &str) -> Value {
+    match data_type.to_lowercase().as_str() {
+        "string" | "str" | "text" => json!({ "type": "string" }),
+        "number" | "float" | "double" => json!({ "type": "number" }),
+        "integer" | "int" => json!({ "type": "integer" }),
+        "boolean" | "bool" => json!({ "type": "boolean" }),
+        "array" | "list" => json!({ "type": "array" }),
+        "object" | "dict" | "map" => json!({ "type": "object" }),
+        _ => json!({}),
+    }
+}
+
+/// The schema for the JSON body of a single call to `tool`
+fn tool_schema(tool: &ToolDefinition) -> Value {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for argument in tool.arguments.iter().flatten() {
+        properties.insert(argument.name.clone(), argument_schema(&argument.data_type));
+        required.push(Value::String(argument.name.clone()));
+    }
+    json!({
+        "type": "object",
+        "properties": {
+            "name": { "const": tool.name },
+            "arguments": {
+                "type": "object",
+                "properties": properties,
+                "required": required,
+            },
+        },
+        "required": ["name", "arguments"],
+    })
+}
+
+/// The tools in the shape Hugging Face chat templates expect,
+/// `{"type": "function", "function": {"name", "description", "parameters"}}`
+pub fn template_tools(tools: &[ToolDefinition]) -> Vec<Value> {
+    tools
+        .iter()
+        .map(|tool| {
+            let mut properties = Map::new();
+            let mut required = Vec::new();
+            for argument in tool.arguments.iter().flatten() {
+                let mut schema = argument_schema(&argument.data_type);
+                schema["description"] = Value::String(argument.description.clone());
+                properties.insert(argument.name.clone(), schema);
+                required.push(Value::String(argument.name.clone()));
+            }
+            json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": {
+                        "type": "object",
+                        "properties": properties,
+                        "required": required,
+                    },
+                },
+            })
+        })
+        .collect()
+}
+
+const TOOL_CALL_OPEN: &str = "<tool_call>";
+const TOOL_CALL_CLOSE: &str = "</tool_call>";
+
+/// Rules for `tool-text`, any text that doesn't contain the `<tool_call>` opener
+/// Each `tool-text-N` rule is the state after matching the first N characters of the
+/// opener, so text can still hold `<`, `<t` or `<tool_` without starting a call.
+/// Since the opener's `<` doesn't come up again in it, a mismatch either starts over on a
+/// new `<` or goes back to plain text
+fn tool_text_rules() -> String {
+    let opener: Vec<char> = TOOL_CALL_OPEN.chars().collect();
+    let state = |matched: usize| {
+        if matched == 0 {
+            "tool-text".to_string()
+        } else {
+            format!("tool-text-{}", matched)
+        }
+    };
+    let mut rules = Vec::new();
+    for matched in 0..opener.len() {
+        let next = opener[matched];
+        let mut alternatives = Vec::new();
+        if matched + 1 < opener.len() {
+            alternatives.push(format!("\"{}\" {}", next, state(matched + 1)));
+        }
+        if matched > 0 {
+            alternatives.push(format!("\"<\" {}", state(1)));
+            alternatives.push(format!("[^{}<] {}", next, state(0)));
+        } else {
+            alternatives.push(format!("[^<] {}", state(0)));
+        }
+        rules.push(format!(
+            "{} ::= ({})?",
+            state(matched),
+            alternatives.join(" | ")
+        ));
+    }
+    rules.join("\n")
+}
+
+/// Build a grammar that forces every `<tool_call>` block to name one of `tools` and
+/// pass arguments of the declared types. Outside of a call the model can talk freely,
+/// unless `tool_call_only` is set in which case it can only emit calls
+pub fn build_tool_grammar(
+    tools: &[ToolDefinition],
+    tool_call_only: bool,
+) -> Result<String, String> {
+    let schema = json!({ "anyOf": tools.iter().map(tool_schema).collect::<Vec<_>>() });
+    let rules = schema_rules(&schema, "tool-call-json").map_err(|e| e.to_string())?;
+    let root = if tool_call_only {
+        r#"root ::= tool-call (tool-ws tool-call)*"#
+    } else {
+        r#"root ::= tool-text (tool-call tool-text)*"#
+    };
+    Ok(format!(
+        r#"{}
+{}
+tool-call ::= "{}" tool-ws tool-call-json tool-ws "{}"
+tool-ws ::= [ \t\n]*
+{}"#,
+        root,
+        tool_text_rules(),
+        TOOL_CALL_OPEN,
+        TOOL_CALL_CLOSE,
+        rules
+    ))
+}
+
+/// Parse the body of a `<tool_call>` block and check it against the declared tools
+/// # Errors
+/// A message saying why the call was rejected, so it can be passed back to the client
+pub fn parse_tool_call(tool_call: &str, tools: &[ToolDefinition]) -> Result<ToolCall, String> {
+    let value: Value = serde_json::from_str(tool_call.trim())
+        .map_err(|e| format!("tool call is not valid JSON: {}", e))?;
+    let call: ToolCall = serde_json::from_value(value.clone())
+        .map_err(|e| format!("tool call is malformed: {}", e))?;
+    let tool = tools
+        .iter()
+        .find(|t| t.name == call.name)
+        .ok_or_else(|| format!("tool {} was not declared", call.name))?;
+    json_schema::validate(&tool_schema(tool), &value)
+        .map_err(|e| format!("bad arguments for {}: {}", call.name, e))?;
+    Ok(call)
 }
 
-pub fn parse_tool_call(tool_call: &str) -> Option<ToolCall> {
-    serde_json::from_str(tool_call).unwrap_or(None)
+/// Pull every `<tool_call>` block out of a finished response
+/// Returns the text left over outside the blocks and the result of parsing each block
+pub fn extract_tool_calls(
+    text: &str,
+    tools: &[ToolDefinition],
+) -> (String, Vec<Result<ToolCall, String>>) {
+    let mut remaining = String::new();
+    let mut calls = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(TOOL_CALL_OPEN) {
+        remaining.push_str(&rest[..start]);
+        let body = &rest[start + TOOL_CALL_OPEN.len()..];
+        match body.find(TOOL_CALL_CLOSE) {
+            Some(end) => {
+                calls.push(parse_tool_call(&body[..end], tools));
+                rest = &body[end + TOOL_CALL_CLOSE.len()..];
+            }
+            None => {
+                calls.push(Err("tool call was never closed".to_string()));
+                rest = "";
+            }
+        }
+    }
+    remaining.push_str(rest);
+    (remaining, calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ToolArguments;
+
+    fn weather_tool() -> ToolDefinition {
+        ToolDefinition {
+            name: "get_weather".to_string(),
+            description: "Current weather for a city".to_string(),
+            arguments: Some(vec![
+                ToolArguments {
+                    name: "city".to_string(),
+                    description: "City name".to_string(),
+                    data_type: "string".to_string(),
+                },
+                ToolArguments {
+                    name: "days".to_string(),
+                    description: "Forecast length".to_string(),
+                    data_type: "int".to_string(),
+                },
+            ]),
+        }
+    }
+
+    #[test]
+    fn tool_text_only_excludes_the_opener() {
+        let rules = tool_text_rules();
+        let lines: Vec<&str> = rules.lines().collect();
+        assert_eq!(lines.len(), TOOL_CALL_OPEN.len());
+        assert_eq!(
+            lines[0],
+            r#"tool-text ::= ("<" tool-text-1 | [^<] tool-text)?"#
+        );
+        assert_eq!(
+            lines[1],
+            r#"tool-text-1 ::= ("t" tool-text-2 | "<" tool-text-1 | [^t<] tool-text)?"#
+        );
+        // Everything but the final `>` is still plain text
+        assert_eq!(
+            lines[10],
+            r#"tool-text-10 ::= ("<" tool-text-1 | [^><] tool-text)?"#
+        );
+    }
+
+    #[test]
+    fn tool_grammar_writes_the_name_first() {
+        let grammar = build_tool_grammar(&[weather_tool()], false).unwrap();
+        let call = grammar
+            .lines()
+            .find(|line| line.starts_with("tool-call-json-0 ::="))
+            .unwrap();
+        assert!(call.find("name-kv").unwrap() < call.find("arguments-kv").unwrap());
+        assert!(grammar.starts_with("root ::= tool-text (tool-call tool-text)*"));
+    }
+
+    #[test]
+    fn parse_tool_call_accepts_declared_calls() {
+        let call = parse_tool_call(
+            r#" {"name": "get_weather", "arguments": {"city": "Oslo", "days": 3}} "#,
+            &[weather_tool()],
+        )
+        .unwrap();
+        assert_eq!(call.name, "get_weather");
+        assert_eq!(call.arguments.unwrap()["days"], 3);
+    }
+
+    #[test]
+    fn parse_tool_call_rejects_bad_calls() {
+        let tools = [weather_tool()];
+        assert!(parse_tool_call("{not json", &tools).is_err());
+        assert!(parse_tool_call(r#"{"arguments": {}}"#, &tools).is_err());
+        assert!(parse_tool_call(r#"{"name": "other", "arguments": {}}"#, &tools).is_err());
+        assert!(parse_tool_call(
+            r#"{"name": "get_weather", "arguments": {"city": "Oslo"}}"#,
+            &tools
+        )
+        .is_err());
+        assert!(parse_tool_call(
+            r#"{"name": "get_weather", "arguments": {"city": "Oslo", "days": "3"}}"#,
+            &tools
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn extract_tool_calls_splits_text_and_calls() {
+        let text = r#"Checking <tool_call>{"name": "get_weather", "arguments": {"city": "Oslo", "days": 1}}</tool_call> now <tool_call>{"#;
+        let (remaining, calls) = extract_tool_calls(text, &[weather_tool()]);
+        assert_eq!(remaining, "Checking  now ");
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].is_ok());
+        assert!(calls[1].is_err());
+    }
 }
@@ -1,6 +1,21 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::extract::FromRef;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use shurbai::types::ModelDefinition;
+use serde_json::{Map, Value};
+use shurbai::embedding_cache::{CacheStats, EmbeddingCache};
+use shurbai::gguf::SpecialToken;
+use shurbai::grammar::JsonRoot;
+use shurbai::manager::{ModelStatus, Routing};
+use shurbai::rerank::RerankMethod;
+use shurbai::types::{
+    Capability, ChatTemplateConfig, ChunkingOptions, EmbeddingNormalization, InputType,
+    LoraAdapterConfig, LoraSelection, ModelConfig, ModelDefinition, ModelManager, PoolingType,
+    SamplingParams, TemplateFormat,
+};
+
+use crate::collections::{Collection, CollectionStore, ScoredDocument, Similarity};
 
 /// XML proccessing structs:
 #[derive(Debug)]
@@ -38,6 +53,57 @@ pub struct StatusMessage {
 pub struct Config {
     //   pub host: String, // for when I make this deployable
     pub models: Vec<ModelDefinition>,
+    pub collections_path: Option<String>, // default: ./collections.json
+    pub embedding_cache: Option<EmbeddingCacheConfig>,
+    pub memory_budget_mb: Option<u64>, // default: unlimited, idle models are unloaded to stay under it
+    pub admin_token: Option<String>,   // Bearer token for the /admin routes, they're off when unset
+    pub default_model: Option<String>, // Serves requests that leave the model out
+    #[serde(default)]
+    pub fallback_models: Vec<String>, // Tried in order when a model isn't configured or won't load
+}
+
+impl Config {
+    pub fn routing(&self) -> Routing {
+        Routing {
+            default_model: self.default_model.clone(),
+            fallback_models: self.fallback_models.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct EmbeddingCacheConfig {
+    pub capacity: Option<usize>,      // default: 4096, 0 turns the cache off
+    pub path: Option<String>, // Directory to keep entries in across restarts, default: memory only
+    pub disk_capacity: Option<usize>, // default: 65536, files past it are deleted least recently used first
+}
+
+/// Everything the handlers share, routes that only need the models can keep taking
+/// `State<Arc<ModelManager>>`
+#[derive(Clone)]
+pub struct AppState {
+    pub model_manager: Arc<ModelManager>,
+    pub collections: Arc<CollectionStore>,
+    pub embedding_cache: Arc<EmbeddingCache>,
+    pub admin_token: Option<String>,
+}
+
+impl FromRef<AppState> for Arc<ModelManager> {
+    fn from_ref(state: &AppState) -> Self {
+        state.model_manager.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<CollectionStore> {
+    fn from_ref(state: &AppState) -> Self {
+        state.collections.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<EmbeddingCache> {
+    fn from_ref(state: &AppState) -> Self {
+        state.embedding_cache.clone()
+    }
 }
 /// API structs:
 
@@ -63,21 +129,95 @@ pub struct LlmParams {
     pub temperature: Option<f32>,
     pub top_p: Option<f32>,
     pub top_k: Option<i32>,
+    pub min_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    pub tfs_z: Option<f32>,
     pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<i32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub dry_multiplier: Option<f32>,
+    pub dry_base: Option<f32>,
+    pub dry_allowed_length: Option<i32>,
+    pub dry_penalty_last_n: Option<i32>,
+    pub dry_sequence_breakers: Option<Vec<String>>,
+    pub mirostat: Option<i32>,
+    pub mirostat_tau: Option<f32>,
+    pub mirostat_eta: Option<f32>,
+    pub seed: Option<u32>,
 }
+
+impl LlmParams {
+    /// Override the model's sampling options with whatever was set on the request
+    pub fn apply(&self, mut params: SamplingParams) -> SamplingParams {
+        params.temperature = self.temperature.unwrap_or(params.temperature);
+        params.top_p = self.top_p.unwrap_or(params.top_p);
+        params.top_k = self.top_k.unwrap_or(params.top_k);
+        params.min_p = self.min_p.unwrap_or(params.min_p);
+        params.typical_p = self.typical_p.unwrap_or(params.typical_p);
+        params.tfs_z = self.tfs_z.unwrap_or(params.tfs_z);
+        params.repeat_penalty = self.repeat_penalty.unwrap_or(params.repeat_penalty);
+        params.repeat_last_n = self.repeat_last_n.unwrap_or(params.repeat_last_n);
+        params.presence_penalty = self.presence_penalty.unwrap_or(params.presence_penalty);
+        params.frequency_penalty = self.frequency_penalty.unwrap_or(params.frequency_penalty);
+        params.dry_multiplier = self.dry_multiplier.unwrap_or(params.dry_multiplier);
+        params.dry_base = self.dry_base.unwrap_or(params.dry_base);
+        params.dry_allowed_length = self.dry_allowed_length.unwrap_or(params.dry_allowed_length);
+        params.dry_penalty_last_n = self.dry_penalty_last_n.unwrap_or(params.dry_penalty_last_n);
+        if let Some(breakers) = &self.dry_sequence_breakers {
+            params.dry_sequence_breakers = breakers.clone();
+        }
+        params.mirostat = self.mirostat.unwrap_or(params.mirostat);
+        params.mirostat_tau = self.mirostat_tau.unwrap_or(params.mirostat_tau);
+        params.mirostat_eta = self.mirostat_eta.unwrap_or(params.mirostat_eta);
+        params.seed = self.seed.or(params.seed);
+        params
+    }
+}
+/// Constrain the output to a structured format
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonSchema { schema: Value },
+}
+
+/// Native JSON mode, `json` forces an object and `json_array` an array
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum OutputFormat {
+    #[serde(rename = "json", alias = "json_object")]
+    Json,
+    #[serde(rename = "json_array")]
+    JsonArray,
+}
+
+impl OutputFormat {
+    pub fn json_root(&self) -> JsonRoot {
+        match self {
+            OutputFormat::Json => JsonRoot::Object,
+            OutputFormat::JsonArray => JsonRoot::Array,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GenerateCall {
-    pub model: String,
+    pub model: Option<String>, // default: the server's default_model
     pub prompt: String,
     pub stream: Option<bool>,
     pub generate_params: Option<LlmParams>,
+    pub grammar: Option<String>, // GBNF grammar the output has to follow
+    pub response_format: Option<ResponseFormat>,
+    pub format: Option<OutputFormat>,
+    pub regex: Option<String>, // The whole output has to match this pattern
+    pub lora_adapters: Option<Vec<LoraSelection>>, // default: every adapter at its configured scale
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ToolArguments {
-    name: String,
-    description: String,
-    data_type: String,
+    pub name: String,
+    pub description: String,
+    pub data_type: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -116,12 +256,37 @@ impl Message {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatGenerateCall {
-    pub model: String,
+    pub model: Option<String>, // default: the server's default_model
     pub messages: Vec<Message>,
     pub stream: Option<bool>,
     pub generate_params: Option<LlmParams>,
     pub tool_call_only: Option<bool>, // set this to only call tools and nothing else
     pub tools: Option<Vec<ToolDefinition>>,
+    pub grammar: Option<String>, // GBNF grammar the output has to follow
+    pub response_format: Option<ResponseFormat>,
+    pub format: Option<OutputFormat>,
+    pub regex: Option<String>, // The whole output has to match this pattern
+    pub rag: Option<RagOptions>, // Answer from the passages of a collection
+    pub lora_adapters: Option<Vec<LoraSelection>>, // default: every adapter at its configured scale
+}
+
+/// Retrieval settings for a chat request, the latest user turn is used as the query
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RagOptions {
+    pub collection: String,
+    pub top_k: Option<usize>, // default: 4
+    pub filter: Option<Map<String, Value>>,
+    pub template: Option<String>, // TinyTemplate with `question` and `passages`, see prompt::RAG_TEMPLATE
+}
+
+/// A retrieved passage, `index` is the number the model was told to cite it by
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Citation {
+    pub index: usize,
+    pub id: String,
+    pub score: f32,
+    pub text: String,
+    pub metadata: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -131,6 +296,8 @@ pub struct GenerateResponse {
     pub response: String,
     pub took: u128,
     pub halt_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parsed: Option<Value>, // The output object when a json_schema response format was requested
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -142,6 +309,10 @@ pub struct ChatGenerateResponse {
     pub halt_reason: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parsed: Option<Value>, // The output object when a json_schema response format was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub citations: Option<Vec<Citation>>, // The passages the answer refers to
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -160,6 +331,8 @@ pub struct ChatGenerateResponseChuck {
     pub model: String,
     pub halt_reason: Option<String>,
     pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub citations: Option<Vec<Citation>>, // Sent once, before the first token
 }
 
 impl ChatGenerateResponseChuck {
@@ -171,6 +344,7 @@ impl ChatGenerateResponseChuck {
             model: model.to_string(),
             halt_reason: None,
             tool_calls: None,
+            citations: None,
         }
     }
 
@@ -182,6 +356,19 @@ impl ChatGenerateResponseChuck {
             model: model.to_string(),
             halt_reason: None,
             tool_calls: Some(tool_calls),
+            citations: None,
+        }
+    }
+
+    pub fn new_citations(model: &str, citations: Vec<Citation>) -> ChatGenerateResponseChuck {
+        ChatGenerateResponseChuck {
+            meta: ServerMetadata::new(),
+            token_str: "".to_string(),
+            role: "assistant".to_string(),
+            model: model.to_string(),
+            halt_reason: None,
+            tool_calls: None,
+            citations: Some(citations),
         }
     }
 
@@ -193,34 +380,160 @@ impl ChatGenerateResponseChuck {
             model: "".to_string(),
             halt_reason: Some(halt_reason.to_string()),
             tool_calls: None,
+            citations: None,
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingData {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<EncodedEmbedding>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub token_embeddings: Vec<EncodedEmbedding>, // Only set when pooling is none
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<EncodedChunk>, // Only set when chunking without aggregation
+    pub tokens: usize,
+}
+
+/// How embeddings are written in the response
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingEncoding {
+    #[default]
+    #[serde(alias = "float")]
+    Float32,
+    Int8,   // Each value scaled by 127 and rounded, meant for normalized vectors
+    Binary, // One sign bit per value, packed 8 to a byte with the first value in the high bit
+    Base64, // The little endian float32 bytes
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EncodedEmbedding {
+    Float32(Vec<f32>),
+    Int8(Vec<i8>),
+    Binary(Vec<u8>),
+    Base64(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncodedChunk {
+    pub start: usize,
+    pub end: usize,
+    pub embedding: EncodedEmbedding,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EmbeddingsResponse {
     pub meta: ServerMetadata,
     pub model: String,
-    pub embeddings: Vec<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embeddings: Option<EncodedEmbedding>, // Only set when a single string prompt was sent
+    pub data: Vec<EmbeddingData>, // One entry per input, in input order
+    pub total_tokens: usize,
+}
+
+/// Either a single input or a batch of them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EmbeddingsInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+impl EmbeddingsInput {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingsInput::Single(prompt) => vec![prompt],
+            EmbeddingsInput::Batch(prompts) => prompts,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EmbeddingsRequest {
+    pub model: Option<String>, // default: the server's default_model
+    #[serde(alias = "input")]
+    pub prompt: EmbeddingsInput,
+    pub pooling: Option<PoolingType>,
+    pub normalization: Option<EmbeddingNormalization>,
+    pub chunking: Option<ChunkingOptions>, // Split long inputs instead of rejecting them
+    pub dimensions: Option<usize>, // Truncate to this many dimensions, for Matryoshka models
+    pub encoding_format: Option<EmbeddingEncoding>, // default: float32
+    pub input_type: Option<InputType>, // Adds the model's prefix for this task, if it has one
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingCacheResponse {
+    pub meta: ServerMetadata,
+    pub stats: CacheStats,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RerankRequest {
+    pub model: Option<String>, // default: the server's default_model
+    pub query: String,
+    pub documents: Vec<String>,
+    pub top_n: Option<usize>,           // default: every document
+    pub return_documents: Option<bool>, // default: false
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RerankResult {
+    pub index: usize, // Position of the document in the request
+    pub relevance_score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RerankResponse {
+    pub meta: ServerMetadata,
     pub model: String,
-    pub prompt: String,
+    pub method: RerankMethod,
+    pub results: Vec<RerankResult>, // Best first
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModelListObject {
     pub name: String,
+    pub aliases: Vec<String>,
     pub type_str: String,
+    pub capabilities: Vec<Capability>,
+    pub loaded: bool,
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ListModelsResponse {
     pub meta: ServerMetadata,
+    pub default_model: Option<String>,
     pub models: Vec<ModelListObject>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelDetailsResponse {
+    pub meta: ServerMetadata,
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub path: String,
+    pub loaded: bool,
+    pub capabilities: Vec<Capability>,
+    pub estimated_bytes: u64,
+    pub file_size: Option<u64>,
+    pub architecture: Option<String>,
+    pub parameter_count: u64,
+    pub quantization: Option<String>,
+    pub context_length: Option<u64>, // What it was trained with, config.num_ctx is what it runs with
+    pub vocab_size: Option<u64>,
+    pub embedding_length: Option<u64>,
+    pub chat_template: Option<String>, // The template embedded in the GGUF, if any
+    pub special_tokens: BTreeMap<String, SpecialToken>,
+    pub gguf_version: u32,
+    pub config: ModelConfig, // With defaults filled in
+    pub lora_adapters: Vec<LoraAdapterConfig>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ErrorResponse {
     pub meta: ServerMetadata,
@@ -235,3 +548,108 @@ impl ErrorResponse {
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateCollectionRequest {
+    pub name: String,
+    pub model: String, // Must be an embedding model, every document and query goes through it
+    #[serde(default)]
+    pub similarity: Similarity,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CollectionInfo {
+    pub name: String,
+    pub model: String,
+    pub similarity: Similarity,
+    pub dimensions: Option<usize>,
+    pub documents: usize,
+}
+
+impl From<&Collection> for CollectionInfo {
+    fn from(collection: &Collection) -> Self {
+        Self {
+            name: collection.name.clone(),
+            model: collection.model.clone(),
+            similarity: collection.similarity,
+            dimensions: collection.dimensions,
+            documents: collection.documents.len(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListCollectionsResponse {
+    pub meta: ServerMetadata,
+    pub collections: Vec<CollectionInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocumentInput {
+    pub id: Option<String>, // default: a hash of the text
+    pub text: String,
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpsertDocumentsRequest {
+    pub documents: Vec<DocumentInput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpsertDocumentsResponse {
+    pub meta: ServerMetadata,
+    pub ids: Vec<String>,
+    pub total_tokens: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueryCollectionRequest {
+    pub text: Option<String>,
+    pub vector: Option<Vec<f32>>, // Used as is, skipping the embedding model
+    pub top_k: Option<usize>,     // default: 10
+    pub filter: Option<Map<String, Value>>, // Exact match per key, an array matches any item
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueryCollectionResponse {
+    pub meta: ServerMetadata,
+    pub results: Vec<ScoredDocument>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoadModelRequest {
+    #[serde(flatten)]
+    pub definition: ModelDefinition,
+    pub load: Option<bool>, // default: true, false only registers it to be loaded on first use
+}
+
+/// Either part can be left out to keep what the model has now
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateModelRequest {
+    pub config: Option<ModelConfig>,
+    pub chat_template: Option<ChatTemplateConfig>,
+    pub jinja_template: Option<String>,
+    pub template_format: Option<TemplateFormat>,
+    pub lora_adapters: Option<Vec<LoraAdapterConfig>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AdminModel {
+    #[serde(flatten)]
+    pub status: ModelStatus,
+    pub definition: ModelDefinition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AdminModelResponse {
+    pub meta: ServerMetadata,
+    pub model: AdminModel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AdminModelsResponse {
+    pub meta: ServerMetadata,
+    pub models: Vec<AdminModel>,
+}
@@ -1,25 +1,218 @@
 use std::sync::Arc;
 
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use serde::Serialize;
-use shurbai::types::ModelManager;
+use serde_json::Value;
+use shurbai::embeddings::is_invalid_input;
+use shurbai::grammar::{json_grammar, validate_grammar};
+use shurbai::json_schema::{self, schema_to_grammar};
+use shurbai::regex_grammar::regex_to_grammar;
+use shurbai::types::{Capability, GrammarConstraint, ModelManager, ModelState, SamplingParams};
 use tokio::sync::{mpsc::Sender, Mutex};
 
-use crate::types::XmlState;
+use crate::types::{
+    Citation, EmbeddingEncoding, EncodedEmbedding, LlmParams, OutputFormat, ResponseFormat,
+    XmlState,
+};
 
 pub fn has_model(model_manager: &ModelManager, model_name: &String) -> bool {
-    model_manager.models.contains_key(model_name)
+    model_manager.has_model(model_name)
+}
+
+/// Pick the model a request is served by, see `ModelManager::route`
+/// Returns the message for the 404 when there's none
+pub fn route_model(
+    model_manager: &ModelManager,
+    requested: Option<&str>,
+    accepts: &[Capability],
+) -> Result<String, String> {
+    model_manager
+        .route(requested, accepts)
+        .ok_or_else(|| match requested {
+            Some(requested) => format!("Model {} not found", requested),
+            None => "No model given and no default_model is configured".to_string(),
+        })
+}
+
+/// Check the model can serve a route, returning the message for the 400 when it can't
+/// This doesn't load the model, capabilities are known from its GGUF header
+pub fn check_capability(
+    model_manager: &ModelManager,
+    model_name: &str,
+    capability: Capability,
+) -> Result<(), String> {
+    let capabilities = model_manager.capabilities(model_name).unwrap_or_default();
+    if capabilities.contains(&capability) {
+        Ok(())
+    } else {
+        let supported: Vec<&str> = capabilities.iter().map(|c| c.as_str()).collect();
+        Err(format!(
+            "Model {} does not support {} (supports: {})",
+            model_name,
+            capability.as_str(),
+            supported.join(", ")
+        ))
+    }
+}
+
+/// Work out the grammar a request should be generated with and make sure it parses
+/// Returns the error message to send back as a 400 when the request can't be honored
+pub fn resolve_grammar(
+    grammar: Option<&String>,
+    response_format: Option<&ResponseFormat>,
+    format: Option<&OutputFormat>,
+    regex: Option<&String>,
+) -> Result<Option<GrammarConstraint>, String> {
+    let schema = match response_format {
+        Some(ResponseFormat::JsonSchema { schema }) => Some(schema),
+        _ => None,
+    };
+    let set = [
+        grammar.is_some(),
+        schema.is_some(),
+        format.is_some(),
+        regex.is_some(),
+    ];
+    if set.iter().filter(|s| **s).count() > 1 {
+        return Err(
+            "only one of grammar, format, regex and a json_schema response_format can be set"
+                .to_string(),
+        );
+    }
+    let constraint = if let Some(grammar) = grammar {
+        Some(GrammarConstraint::new(grammar.clone()))
+    } else if let Some(schema) = schema {
+        Some(GrammarConstraint::json(
+            schema_to_grammar(schema).map_err(|e| format!("invalid json schema: {}", e))?,
+        ))
+    } else if let Some(format) = format {
+        Some(GrammarConstraint::json(
+            json_grammar(format.json_root()).to_string(),
+        ))
+    } else if let Some(regex) = regex {
+        Some(GrammarConstraint::new(
+            regex_to_grammar(regex).map_err(|e| format!("invalid regex: {}", e))?,
+        ))
+    } else {
+        None
+    };
+    if let Some(constraint) = &constraint {
+        validate_grammar(&constraint.grammar).map_err(|e| e.to_string())?;
+    }
+    Ok(constraint)
+}
+
+/// Parse and validate the output of a structured request
+/// Returns `Ok(None)` when the request didn't ask for structured output
+pub fn parse_structured_output(
+    response_format: Option<&ResponseFormat>,
+    output: &str,
+) -> Result<Option<Value>, String> {
+    let Some(ResponseFormat::JsonSchema { schema }) = response_format else {
+        return Ok(None);
+    };
+    let value: Value = serde_json::from_str(output.trim())
+        .map_err(|e| format!("model output is not valid JSON: {}", e))?;
+    json_schema::validate(schema, &value)
+        .map_err(|e| format!("model output does not match the schema: {}", e))?;
+    Ok(Some(value))
+}
+
+/// Resolve the sampling options against the model's config, and the token limit (512 unless
+/// the request sets `max_tokens`)
+pub fn generation_options(
+    model_state: &ModelState,
+    params: Option<&LlmParams>,
+) -> (SamplingParams, i32) {
+    let defaults = model_state.config.sampling_params();
+    let max_tokens = params.and_then(|p| p.max_tokens).unwrap_or(512);
+    let sampling = match params {
+        Some(p) => p.apply(defaults),
+        None => defaults,
+    };
+    (sampling, max_tokens)
+}
+
+/// Keep the passages the answer cites with `[n]`, or all of them if it cites none
+pub fn cited_passages(answer: &str, passages: Vec<Citation>) -> Vec<Citation> {
+    let cited: Vec<Citation> = passages
+        .iter()
+        .filter(|p| answer.contains(&format!("[{}]", p.index)))
+        .cloned()
+        .collect();
+    if cited.is_empty() {
+        passages
+    } else {
+        cited
+    }
+}
+
+/// Write an embedding out in the requested encoding
+pub fn encode_embedding(embedding: &[f32], encoding: EmbeddingEncoding) -> EncodedEmbedding {
+    match encoding {
+        EmbeddingEncoding::Float32 => EncodedEmbedding::Float32(embedding.to_vec()),
+        EmbeddingEncoding::Int8 => EncodedEmbedding::Int8(
+            embedding
+                .iter()
+                .map(|v| (v * 127.0).round().clamp(-127.0, 127.0) as i8)
+                .collect(),
+        ),
+        EmbeddingEncoding::Binary => EncodedEmbedding::Binary(
+            embedding
+                .chunks(8)
+                .map(|bits| {
+                    bits.iter().enumerate().fold(0u8, |byte, (i, v)| {
+                        if *v > 0.0 {
+                            byte | (0x80 >> i)
+                        } else {
+                            byte
+                        }
+                    })
+                })
+                .collect(),
+        ),
+        EmbeddingEncoding::Base64 => {
+            let bytes: Vec<u8> = embedding.iter().flat_map(|v| v.to_le_bytes()).collect();
+            EncodedEmbedding::Base64(STANDARD.encode(bytes))
+        }
+    }
 }
 
 #[macro_export]
+/// Get a model from the manager, loading it if needed, or return a 503 from the handler
+/// Falls back to a model that can do one of the capabilities when it won't load, evaluating
+/// to the name of the model that was picked and the model
 macro_rules! get_model {
-    ($model_manager:expr, $model_name:expr) => {
-        $model_manager
-            .models
-            .get($model_name)
-            .expect("Model not found")
+    ($model_manager:expr, $model_name:expr, $accepts:expr) => {
+        match $crate::utils::blocking($model_manager.clone(), {
+            let model_name = $model_name.to_string();
+            let accepts = $accepts.to_vec();
+            move |model_manager| model_manager.get_or_fallback(&model_name, &accepts)
+        })
+        .await
+        {
+            Ok(picked) => picked,
+            Err(e) => {
+                return axum::response::IntoResponse::into_response((
+                    axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                    axum::Json($crate::types::ErrorResponse::new(&e.to_string())),
+                ))
+            }
+        }
     };
 }
 
+/// Run a model manager call on the blocking pool
+/// Loading a model reads the whole file and running one decodes on the CPU or GPU, either
+/// takes far too long to hold up an async worker
+pub async fn blocking<T: Send + 'static>(
+    model_manager: Arc<ModelManager>,
+    f: impl FnOnce(&ModelManager) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(move || f(&model_manager)).await?
+}
+
 pub fn send_to_stream<T>(tx_arc: Arc<Mutex<Sender<T>>>, body: &T)
 where
     T: Serialize + Clone + Send + 'static,
@@ -73,3 +266,12 @@ pub fn process_xml_token(xml_state: &mut XmlState, token: &str) -> Option<String
 
     return None;
 }
+
+/// 400 when an embedding or rerank request can't be served as given, 500 when the model failed
+pub fn embedding_error_status(error: &anyhow::Error) -> StatusCode {
+    if is_invalid_input(error) {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
@@ -0,0 +1,293 @@
+//! An LRU cache in front of `generate_embeddings`, optionally backed by a directory on disk
+//! Entries are keyed by the model (including which file it was loaded from), the resolved
+//! pooling options and the input text
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    embeddings::generate_embeddings,
+    lru::Lru,
+    types::{EmbeddingNormalization, EmbeddingOptions, EmbeddingResult, ModelState},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+    pub disk_entries: usize,
+    pub disk_capacity: usize,
+}
+
+/// A cached result along with the text it's for, so a hash collision can't return
+/// another input's embedding
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedEmbedding {
+    text: String,
+    result: EmbeddingResult,
+}
+
+pub struct EmbeddingCache {
+    capacity: usize,
+    dir: Option<PathBuf>,
+    disk_capacity: usize,
+    state: Mutex<Lru<u64, CachedEmbedding>>,
+    /// The keys with a file in `dir`, the least recently used file is deleted once it's full
+    disk: Mutex<Lru<u64, ()>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+fn disk_file_name(key: u64) -> String {
+    format!("{:016x}.json", key)
+}
+
+/// FNV-1a, unlike `DefaultHasher` it's stable between builds so disk entries stay valid
+pub fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        // Separate the parts so ("ab", "c") and ("a", "bc") don't collide
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl EmbeddingCache {
+    /// A cache holding up to `capacity` results in memory, a capacity of 0 turns it off
+    /// With a `dir` up to `disk_capacity` results are also kept there across restarts
+    /// # Errors
+    /// If the disk directory can't be created or listed
+    pub fn new(capacity: usize, dir: Option<PathBuf>, disk_capacity: usize) -> Result<Self> {
+        let mut disk = Lru::new(disk_capacity);
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir)?;
+            // Oldest first, so the files left from last time are evicted in the order they'd
+            // have been if the server had kept running
+            let mut files = Vec::new();
+            for file in std::fs::read_dir(dir)? {
+                let file = file?;
+                let key = file
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".json"))
+                    .and_then(|stem| u64::from_str_radix(stem, 16).ok());
+                let modified = file.metadata().and_then(|m| m.modified()).ok();
+                if let (Some(key), Some(modified)) = (key, modified) {
+                    files.push((modified, key));
+                }
+            }
+            files.sort();
+            for (_, key) in files {
+                for (evicted, _) in disk.insert(key, ()) {
+                    let _ = std::fs::remove_file(dir.join(disk_file_name(evicted)));
+                }
+            }
+        }
+        Ok(EmbeddingCache {
+            capacity,
+            dir,
+            disk_capacity,
+            state: Mutex::new(Lru::new(capacity)),
+            disk: Mutex::new(disk),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    fn key(model_name: &str, model: &ModelState, options: &EmbeddingOptions, text: &str) -> u64 {
+        // Resolve the defaults so a request that spells them out shares entries with one that doesn't
+        let pooling = options.pooling.unwrap_or_else(|| model.default_pooling());
+        let normalization = options
+            .normalization
+            .or(model.config.embedding_normalization)
+            .unwrap_or(EmbeddingNormalization::L2);
+        // The prefix changes the text that actually gets embedded
+        let prefix = options
+            .input_type
+            .and_then(|t| model.embedding_prefixes.get(t))
+            .unwrap_or("");
+        let settings = serde_json::to_string(&(pooling, normalization, &options.chunking, prefix))
+            .expect("embedding options always serialize");
+        fnv1a(&[
+            model_name.as_bytes(),
+            model.file_signature.as_bytes(),
+            settings.as_bytes(),
+            text.as_bytes(),
+        ])
+    }
+
+    fn disk_path(&self, key: u64) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(disk_file_name(key)))
+    }
+
+    fn lookup(&self, key: u64, text: &str) -> Option<EmbeddingResult> {
+        if let Some(cached) = self.state.lock().unwrap().get(&key) {
+            if cached.text == text {
+                return Some(cached.result.clone());
+            }
+        }
+        let data = std::fs::read_to_string(self.disk_path(key)?).ok()?;
+        let cached: CachedEmbedding = serde_json::from_str(&data).ok()?;
+        if cached.text != text {
+            return None;
+        }
+        let result = cached.result.clone();
+        self.disk.lock().unwrap().get(&key);
+        // Promote it so the next lookup doesn't touch the disk
+        self.state.lock().unwrap().insert(key, cached);
+        Some(result)
+    }
+
+    fn store(&self, key: u64, text: &str, result: &EmbeddingResult) {
+        let cached = CachedEmbedding {
+            text: text.to_string(),
+            result: result.clone(),
+        };
+        if let Some(path) = self.disk_path(key) {
+            // A failed write or delete only costs us a future miss
+            if let Ok(data) = serde_json::to_string(&cached) {
+                if std::fs::write(path, data).is_ok() {
+                    let evicted = self.disk.lock().unwrap().insert(key, ());
+                    for (evicted, _) in evicted {
+                        if let Some(path) = self.disk_path(evicted) {
+                            let _ = std::fs::remove_file(path);
+                        }
+                    }
+                }
+            }
+        }
+        self.state.lock().unwrap().insert(key, cached);
+    }
+
+    /// `generate_embeddings`, but only the inputs that aren't cached get embedded
+    /// # Arguments
+    /// * `model_name` - The name the model is loaded under, part of the cache key
+    /// * `model` - The embedding model
+    /// * `backend` - The llama backend
+    /// * `prompts` - The inputs to embed
+    /// * `options` - Pooling and normalization, unset fields use the model defaults
+    /// # Errors
+    /// Anything `generate_embeddings` can fail with
+    pub fn embed(
+        &self,
+        model_name: &str,
+        model: &ModelState,
+        backend: &LlamaBackend,
+        prompts: &[String],
+        options: &EmbeddingOptions,
+    ) -> Result<Vec<EmbeddingResult>> {
+        if self.capacity == 0 {
+            return generate_embeddings(model, backend, prompts, options);
+        }
+        let keys: Vec<u64> = prompts
+            .iter()
+            .map(|p| Self::key(model_name, model, options, p))
+            .collect();
+        let mut output: Vec<Option<EmbeddingResult>> = keys
+            .iter()
+            .zip(prompts)
+            .map(|(key, text)| self.lookup(*key, text))
+            .collect();
+        let missing: Vec<usize> = (0..prompts.len())
+            .filter(|i| output[*i].is_none())
+            .collect();
+        self.hits
+            .fetch_add((prompts.len() - missing.len()) as u64, Ordering::Relaxed);
+        self.misses
+            .fetch_add(missing.len() as u64, Ordering::Relaxed);
+        if !missing.is_empty() {
+            let inputs: Vec<String> = missing.iter().map(|i| prompts[*i].clone()).collect();
+            let embedded = generate_embeddings(model, backend, &inputs, options)?;
+            for (i, result) in missing.into_iter().zip(embedded) {
+                self.store(keys[i], &prompts[i], &result);
+                output[i] = Some(result);
+            }
+        }
+        Ok(output
+            .into_iter()
+            .map(|r| r.expect("filled above"))
+            .collect())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.state.lock().unwrap().len(),
+            capacity: self.capacity,
+            disk_entries: self.disk.lock().unwrap().len(),
+            disk_capacity: if self.dir.is_some() {
+                self.disk_capacity
+            } else {
+                0
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(value: f32) -> EmbeddingResult {
+        EmbeddingResult {
+            embedding: vec![value],
+            token_embeddings: Vec::new(),
+            chunks: Vec::new(),
+            n_tokens: 1,
+        }
+    }
+
+    #[test]
+    fn fnv1a_is_stable_and_separates_parts() {
+        assert_eq!(fnv1a(&[]), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(&[b"ab", b"c"]), fnv1a(&[b"ab", b"c"]));
+        assert_ne!(fnv1a(&[b"ab", b"c"]), fnv1a(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn lookups_compare_the_text() {
+        let dir = std::env::temp_dir().join(format!("embedding-cache-{}", std::process::id()));
+        let cache = EmbeddingCache::new(4, Some(dir.clone()), 16).unwrap();
+        cache.store(7, "first", &result(1.0));
+        assert!(cache.lookup(7, "second").is_none());
+        assert_eq!(cache.lookup(7, "first").unwrap().embedding, vec![1.0]);
+
+        // A fresh cache only has the disk entry to go on
+        let reopened = EmbeddingCache::new(4, Some(dir.clone()), 16).unwrap();
+        assert!(reopened.lookup(7, "second").is_none());
+        assert_eq!(reopened.lookup(7, "first").unwrap().embedding, vec![1.0]);
+        assert_eq!(reopened.stats().entries, 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn disk_entries_past_the_cap_are_deleted() {
+        let dir = std::env::temp_dir().join(format!("embedding-cache-cap-{}", std::process::id()));
+        let cache = EmbeddingCache::new(4, Some(dir.clone()), 2).unwrap();
+        cache.store(1, "one", &result(1.0));
+        cache.store(2, "two", &result(2.0));
+        cache.store(3, "three", &result(3.0));
+        assert!(!dir.join(disk_file_name(1)).exists());
+        assert!(dir.join(disk_file_name(3)).exists());
+        assert_eq!(cache.stats().disk_entries, 2);
+
+        // Reopening with a smaller cap trims what's already there
+        let reopened = EmbeddingCache::new(4, Some(dir.clone()), 1).unwrap();
+        assert_eq!(reopened.stats().disk_entries, 1);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
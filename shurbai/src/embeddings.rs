@@ -1,55 +1,361 @@
-use anyhow::{bail, Result};
+use std::fmt;
+use std::num::NonZeroU32;
+
+use anyhow::{bail, Context, Result};
 use llama_cpp_2::{
-    context::{params::LlamaContextParams, LlamaContext},
+    context::{
+        params::{LlamaContextParams, LlamaPoolingType},
+        LlamaContext,
+    },
     llama_backend::LlamaBackend,
     llama_batch::LlamaBatch,
     model::AddBos,
+    token::LlamaToken,
+};
+
+use crate::types::{
+    ChunkAggregation, ChunkEmbedding, ChunkingOptions, EmbeddingNormalization, EmbeddingOptions,
+    EmbeddingResult, ModelState, PoolingType,
 };
 
-use crate::{types::ModelState, TokenCallback};
+/// An input that can't be embedded as asked (too long, conflicting options), as opposed to
+/// something going wrong while embedding it
+#[derive(Debug)]
+pub struct InvalidInput(pub String);
+
+impl fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidInput {}
+
+/// Whether an embedding or rerank error was caused by the request rather than the model
+pub fn is_invalid_input(error: &anyhow::Error) -> bool {
+    error.is::<InvalidInput>()
+}
 
+/// Embed a list of inputs, packing as many as fit into each batch
+/// # Arguments
+/// * `model` - The embedding model
+/// * `backend` - The llama backend
+/// * `prompts` - The inputs to embed
+/// * `options` - Pooling and normalization, unset fields use the model defaults
+/// # Returns
+/// One result per input, in the same order as `prompts`
+/// # Errors
+/// If an input is longer than the context window (and chunking is off) or decoding fails
 pub fn generate_embeddings(
     model: &ModelState,
     backend: &LlamaBackend,
-    prompt: &String,
-) -> Result<Vec<f32>> {
+    prompts: &[String],
+    options: &EmbeddingOptions,
+) -> Result<Vec<EmbeddingResult>> {
+    let pooling = options.pooling.unwrap_or_else(|| model.default_pooling());
+    let normalise = options
+        .normalization
+        .or(model.config.embedding_normalization)
+        .unwrap_or(EmbeddingNormalization::L2)
+        == EmbeddingNormalization::L2;
+    let mut ctx = embedding_context(model, backend, pooling)?;
+    let n_ctx = ctx.n_ctx() as usize;
+    let token_level = pooling == PoolingType::None;
+    if token_level && options.chunking.is_some() {
+        bail!(InvalidInput(
+            "Chunking can't be combined with token level (none) pooling".to_string()
+        ));
+    }
+
+    // Every input becomes one or more sequences to embed
+    let mut sequences = Vec::new();
+    let mut n_tokens = Vec::with_capacity(prompts.len());
+    let prefix = options
+        .input_type
+        .and_then(|t| model.embedding_prefixes.get(t))
+        .unwrap_or("");
+    for (i, prompt) in prompts.iter().enumerate() {
+        let tokens = model
+            .model
+            .str_to_token(&format!("{}{}", prefix, prompt), AddBos::Always)
+            .with_context(|| format!("failed to tokenize input {}", i))?;
+        n_tokens.push(tokens.len());
+        match &options.chunking {
+            Some(chunking) => {
+                let bos = model.model.token_bos();
+                sequences.extend(split_windows(i, tokens, bos, chunking, n_ctx)?);
+            }
+            None if n_ctx < tokens.len() => bail!(InvalidInput(format!(
+                "Input {} ({} tokens) exceeds the size of the context window ({})",
+                i,
+                tokens.len(),
+                n_ctx
+            ))),
+            None => sequences.push(Sequence {
+                input: i,
+                start: 0,
+                end: tokens.len(),
+                tokens,
+            }),
+        }
+    }
+
+    let decoded = decode_packed(
+        &mut ctx,
+        sequences.iter().map(|s| s.tokens.as_slice()),
+        token_level,
+        normalise,
+    )?;
+
+    let mut output: Vec<EmbeddingResult> = n_tokens
+        .into_iter()
+        .map(|n_tokens| EmbeddingResult {
+            embedding: Vec::new(),
+            token_embeddings: Vec::new(),
+            chunks: Vec::new(),
+            n_tokens,
+        })
+        .collect();
+    for (sequence, result) in sequences.iter().zip(decoded) {
+        let target = &mut output[sequence.input];
+        if options.chunking.is_some() {
+            target.chunks.push(ChunkEmbedding {
+                start: sequence.start,
+                end: sequence.end,
+                embedding: result.embedding,
+            });
+        } else {
+            target.embedding = result.embedding;
+            target.token_embeddings = result.token_embeddings;
+        }
+    }
+    if let Some(aggregate) = options.chunking.as_ref().and_then(|c| c.aggregate) {
+        for result in output.iter_mut() {
+            let chunks = std::mem::take(&mut result.chunks);
+            let combined = aggregate_chunks(&chunks, aggregate);
+            result.embedding = if normalise {
+                normalize(&combined)
+            } else {
+                combined
+            };
+        }
+    }
+    Ok(output)
+}
+
+/// The most sequences packed into one batch, the context is set up to hold this many
+const MAX_PACKED_SEQUENCES: usize = 64;
+
+/// A context set up to pool its output with `pooling`, sized from the model's `num_ctx`
+/// The whole batch goes through as one micro batch, since non causal models have to see
+/// every token of a sequence at once
+pub(crate) fn embedding_context<'a>(
+    model: &'a ModelState,
+    backend: &LlamaBackend,
+    pooling: PoolingType,
+) -> Result<LlamaContext<'a>> {
+    let context_size = model.context_size() as u32;
     let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(context_size))
+        .with_n_batch(context_size)
+        .with_n_ubatch(context_size)
+        .with_n_seq_max(MAX_PACKED_SEQUENCES as u32)
         .with_n_threads_batch(std::thread::available_parallelism()?.get() as i32)
-        .with_embeddings(true);
-    let mut ctx = model
+        .with_embeddings(true)
+        .with_pooling_type(llama_pooling_type(pooling));
+    model
         .model
-        .new_context(&backend, ctx_params)
-        .expect("Failed to create context");
-    let tokens = model
-        .model
-        .str_to_token(prompt, AddBos::Always)
-        .expect("Failed to tokenize");
+        .new_context(backend, ctx_params)
+        .with_context(|| "Failed to create context")
+}
+
+/// Decode token sequences, packing as many as fit into each batch (up to
+/// `MAX_PACKED_SEQUENCES`). Every sequence has to fit in the context on its own
+pub(crate) fn decode_packed<'t>(
+    ctx: &mut LlamaContext,
+    sequences: impl IntoIterator<Item = &'t [LlamaToken]>,
+    token_level: bool,
+    normalise: bool,
+) -> Result<Vec<EmbeddingResult>> {
     let n_ctx = ctx.n_ctx() as usize;
+    let mut decoded = Vec::new();
+    let mut batch_lens = Vec::new();
+    let mut batch = LlamaBatch::new(n_ctx, 1);
+    for tokens in sequences {
+        // Flush the batch once the next input won't fit alongside the ones already in it
+        if batch.n_tokens() as usize + tokens.len() > n_ctx
+            || batch_lens.len() == MAX_PACKED_SEQUENCES
+        {
+            decoded.extend(batch_decode(
+                ctx,
+                &mut batch,
+                &batch_lens,
+                token_level,
+                normalise,
+            )?);
+            batch_lens.clear();
+        }
+        let max_seq_id_batch = batch_lens.len() as i32;
+        // Token level output needs every position, pooled output only the sequence
+        batch.add_sequence(tokens, max_seq_id_batch, token_level)?;
+        batch_lens.push(tokens.len());
+    }
+    decoded.extend(batch_decode(
+        ctx,
+        &mut batch,
+        &batch_lens,
+        token_level,
+        normalise,
+    )?);
+    Ok(decoded)
+}
+
+/// A run of tokens to embed and where it came from
+struct Sequence {
+    input: usize,
+    start: usize,
+    end: usize,
+    tokens: Vec<LlamaToken>,
+}
 
-    if n_ctx < tokens.len() {
-        bail!("One of the provided prompts exceeds the size of the context window");
+/// Split an input into overlapping windows, each window gets its own BOS token
+fn split_windows(
+    input: usize,
+    tokens: Vec<LlamaToken>,
+    bos: LlamaToken,
+    chunking: &ChunkingOptions,
+    n_ctx: usize,
+) -> Result<Vec<Sequence>> {
+    let has_bos = tokens.first() == Some(&bos);
+    let body_start = if has_bos { 1 } else { 0 };
+    let bos_len = if has_bos { 1 } else { 0 };
+    let window = chunking
+        .window
+        .unwrap_or(n_ctx)
+        .min(n_ctx)
+        .saturating_sub(bos_len);
+    let overlap = chunking.overlap.unwrap_or(0);
+    if window == 0 || overlap >= window {
+        bail!(InvalidInput(
+            "The chunk window must be larger than the overlap".to_string()
+        ));
     }
-    let max_seq_id_batch = 0;
-    let mut batch = LlamaBatch::new(n_ctx, 1);
-    batch.add_sequence(&tokens, max_seq_id_batch, false)?;
-    Ok(batch_decode(&mut ctx, &mut batch, true))
+    let step = window - overlap;
+    let mut sequences = Vec::new();
+    let mut start = body_start;
+    loop {
+        let end = (start + window).min(tokens.len());
+        let mut chunk = Vec::with_capacity(end - start + bos_len);
+        if has_bos {
+            chunk.push(bos);
+        }
+        chunk.extend_from_slice(&tokens[start..end]);
+        sequences.push(Sequence {
+            input,
+            start,
+            end,
+            tokens: chunk,
+        });
+        if end >= tokens.len() {
+            break;
+        }
+        start += step;
+    }
+    Ok(sequences)
 }
 
-fn batch_decode(ctx: &mut LlamaContext, batch: &mut LlamaBatch, normalise: bool) -> Vec<f32> {
+fn aggregate_chunks(chunks: &[ChunkEmbedding], aggregate: ChunkAggregation) -> Vec<f32> {
+    let Some(first) = chunks.first() else {
+        return Vec::new();
+    };
+    let mut combined = first.embedding.clone();
+    for chunk in &chunks[1..] {
+        for (acc, val) in combined.iter_mut().zip(&chunk.embedding) {
+            match aggregate {
+                ChunkAggregation::Mean => *acc += val,
+                ChunkAggregation::Max => *acc = acc.max(*val),
+            }
+        }
+    }
+    if aggregate == ChunkAggregation::Mean {
+        let n = chunks.len() as f32;
+        combined.iter_mut().for_each(|v| *v /= n);
+    }
+    combined
+}
+
+fn llama_pooling_type(pooling: PoolingType) -> LlamaPoolingType {
+    match pooling {
+        PoolingType::None => LlamaPoolingType::None,
+        PoolingType::Mean => LlamaPoolingType::Mean,
+        PoolingType::Cls => LlamaPoolingType::Cls,
+        PoolingType::Last => LlamaPoolingType::Last,
+        PoolingType::Rank => LlamaPoolingType::Rank,
+    }
+}
+
+/// Decode a packed batch, `seq_lens` holds the token count of each sequence in order
+fn batch_decode(
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    seq_lens: &[usize],
+    token_level: bool,
+    normalise: bool,
+) -> Result<Vec<EmbeddingResult>> {
+    if seq_lens.is_empty() {
+        return Ok(Vec::new());
+    }
     ctx.clear_kv_cache();
-    ctx.decode(batch).expect("Failed to decode");
+    ctx.decode(batch).with_context(|| "Failed to decode")?;
 
-    let embedding = ctx.embeddings_seq_ith(0).expect("Failed to get embeddings");
-    let output_embeddings = if normalise {
-        normalize(embedding)
-    } else {
-        embedding.to_vec()
+    let finish = |embedding: &[f32]| {
+        if normalise {
+            normalize(embedding)
+        } else {
+            embedding.to_vec()
+        }
     };
+    let mut output = Vec::with_capacity(seq_lens.len());
+    let mut offset = 0;
+    for (i, n_tokens) in seq_lens.iter().enumerate() {
+        let mut result = EmbeddingResult {
+            embedding: Vec::new(),
+            token_embeddings: Vec::new(),
+            chunks: Vec::new(),
+            n_tokens: *n_tokens,
+        };
+        if token_level {
+            for j in 0..*n_tokens {
+                let embedding = ctx
+                    .embeddings_ith((offset + j) as i32)
+                    .with_context(|| "Failed to get token embeddings")?;
+                result.token_embeddings.push(finish(embedding));
+            }
+        } else {
+            let embedding = ctx
+                .embeddings_seq_ith(i as i32)
+                .with_context(|| "Failed to get embeddings")?;
+            result.embedding = finish(embedding);
+        }
+        offset += n_tokens;
+        output.push(result);
+    }
     batch.clear();
-    output_embeddings
+    Ok(output)
+}
+
+/// Keep the first `dimensions` values of an embedding, for Matryoshka trained models
+/// The shortened vector is re-normalized when `normalise` is set
+pub fn truncate_embedding(embedding: &[f32], dimensions: usize, normalise: bool) -> Vec<f32> {
+    let truncated = &embedding[..dimensions.min(embedding.len())];
+    if normalise {
+        normalize(truncated)
+    } else {
+        truncated.to_vec()
+    }
 }
 
-fn normalize(input: &[f32]) -> Vec<f32> {
+pub fn normalize(input: &[f32]) -> Vec<f32> {
     let magnitude = input
         .iter()
         .fold(0.0, |acc, &val| val.mul_add(val, acc))
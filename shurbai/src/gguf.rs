@@ -0,0 +1,632 @@
+//! A small reader for the metadata section of GGUF files
+//! llama.cpp already parses this on load, but we want to look at keys it doesn't expose
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+/// Arrays longer than this (the vocab, merges, ...) only keep their length
+const MAX_KEPT_ARRAY_LEN: u64 = 64;
+
+/// A single metadata value, big arrays are reduced to their length
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum MetaValue {
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<MetaValue>),
+    ArrayLen { len: u64 },
+}
+
+impl MetaValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            MetaValue::UInt(v) => Some(*v),
+            MetaValue::Int(v) if *v >= 0 => Some(*v as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MetaValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The number of items if this is an array
+    pub fn array_len(&self) -> Option<u64> {
+        match self {
+            MetaValue::Array(items) => Some(items.len() as u64),
+            MetaValue::ArrayLen { len } => Some(*len),
+            _ => None,
+        }
+    }
+}
+
+/// A special token's id and, when the vocab has it, its text
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpecialToken {
+    pub id: u64,
+    pub text: Option<String>,
+}
+
+/// The key value metadata from a GGUF file header, plus what we can tell from the tensor infos
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GgufMetadata {
+    pub version: u32,
+    pub values: HashMap<String, MetaValue>,
+    pub tensor_count: u64,
+    /// Total number of weights across all tensors
+    pub parameter_count: u64,
+    /// Number of weights stored in each ggml type, e.g. `Q4_K`
+    pub tensor_types: BTreeMap<String, u64>,
+    /// Keyed by role without the `_token_id` suffix, e.g. `bos`, `eos`, `eot`
+    pub special_tokens: BTreeMap<String, SpecialToken>,
+}
+
+impl GgufMetadata {
+    pub fn get(&self, key: &str) -> Option<&MetaValue> {
+        self.values.get(key)
+    }
+
+    /// The model architecture, `general.architecture`
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture").and_then(MetaValue::as_str)
+    }
+
+    /// Look up an architecture specific key, `key` is the part after `{arch}.`
+    pub fn arch_value(&self, key: &str) -> Option<&MetaValue> {
+        let arch = self.architecture()?;
+        self.get(&format!("{}.{}", arch, key))
+    }
+
+    /// The context length the model was trained with
+    pub fn context_length(&self) -> Option<u64> {
+        self.arch_value("context_length")
+            .and_then(MetaValue::as_u64)
+    }
+
+    pub fn vocab_size(&self) -> Option<u64> {
+        self.get("tokenizer.ggml.tokens")
+            .and_then(MetaValue::array_len)
+            .or_else(|| self.arch_value("vocab_size").and_then(MetaValue::as_u64))
+    }
+
+    /// The Jinja chat template the model was published with
+    pub fn chat_template(&self) -> Option<&str> {
+        self.get("tokenizer.chat_template")
+            .and_then(MetaValue::as_str)
+    }
+
+    /// The quantization named by `general.file_type`, or the type holding most weights
+    /// when the file doesn't say
+    pub fn quantization(&self) -> Option<String> {
+        if let Some(name) = self
+            .get("general.file_type")
+            .and_then(MetaValue::as_u64)
+            .and_then(file_type_name)
+        {
+            return Some(name.to_string());
+        }
+        self.tensor_types
+            .iter()
+            .max_by_key(|(_, count)| **count)
+            .map(|(name, _)| name.clone())
+    }
+}
+
+/// llama.cpp's `llama_ftype` names
+fn file_type_name(file_type: u64) -> Option<&'static str> {
+    Some(match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        _ => return None,
+    })
+}
+
+/// ggml's `ggml_type` names, as stored in the tensor infos
+fn tensor_type_name(tensor_type: u32) -> String {
+    let name = match tensor_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        6 => "Q5_0",
+        7 => "Q5_1",
+        8 => "Q8_0",
+        9 => "Q8_1",
+        10 => "Q2_K",
+        11 => "Q3_K",
+        12 => "Q4_K",
+        13 => "Q5_K",
+        14 => "Q6_K",
+        15 => "Q8_K",
+        16 => "IQ2_XXS",
+        17 => "IQ2_XS",
+        18 => "IQ3_XXS",
+        19 => "IQ1_S",
+        20 => "IQ4_NL",
+        21 => "IQ3_S",
+        22 => "IQ2_S",
+        23 => "IQ4_XS",
+        24 => "I8",
+        25 => "I16",
+        26 => "I32",
+        27 => "I64",
+        28 => "F64",
+        29 => "IQ1_M",
+        30 => "BF16",
+        _ => return format!("type_{}", tensor_type),
+    };
+    name.to_string()
+}
+
+struct GgufReader<R: Read + Seek> {
+    reader: BufReader<R>,
+    version: u32,
+    /// Where the reader is and how long the file is, lengths are checked against what's left
+    position: u64,
+    len: u64,
+}
+
+impl<R: Read + Seek> GgufReader<R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.reader.read_exact(&mut buf)?;
+        self.position += N as u64;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    /// Version 1 files used 32 bit lengths and counts
+    fn count(&mut self) -> Result<u64> {
+        if self.version == 1 {
+            Ok(self.u32()? as u64)
+        } else {
+            self.u64()
+        }
+    }
+
+    /// Fail on a length that runs past the end of the file rather than trusting it
+    fn check_len(&self, len: u64) -> Result<()> {
+        let remaining = self.len.saturating_sub(self.position);
+        if len > remaining {
+            bail!(
+                "length {} at offset {} runs past the end of the file",
+                len,
+                self.position
+            );
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) -> Result<()> {
+        self.check_len(len)?;
+        self.reader.seek_relative(len as i64)?;
+        self.position += len;
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.count()?;
+        self.check_len(len)?;
+        let mut buf = vec![0u8; len as usize];
+        self.reader.read_exact(&mut buf)?;
+        self.position += len;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn skip_string(&mut self) -> Result<()> {
+        let len = self.count()?;
+        self.skip(len)
+    }
+
+    /// The item count of an array, every item takes at least a byte
+    fn array_len(&mut self) -> Result<u64> {
+        let len = self.count()?;
+        self.check_len(len)?;
+        Ok(len)
+    }
+
+    /// Size in bytes of the fixed size value types
+    fn value_size(value_type: u32) -> Option<u64> {
+        match value_type {
+            0 | 1 | 7 => Some(1),
+            2 | 3 => Some(2),
+            4..=6 => Some(4),
+            10..=12 => Some(8),
+            _ => None,
+        }
+    }
+
+    fn skip_value(&mut self, value_type: u32) -> Result<()> {
+        if let Some(size) = Self::value_size(value_type) {
+            return self.skip(size);
+        }
+        match value_type {
+            8 => self.skip_string(),
+            9 => {
+                let item_type = self.u32()?;
+                let len = self.array_len()?;
+                self.skip_items(item_type, len)
+            }
+            _ => bail!("unknown GGUF value type {}", value_type),
+        }
+    }
+
+    /// Skip `len` array items, in one go when they're all the same size
+    fn skip_items(&mut self, item_type: u32, len: u64) -> Result<()> {
+        match Self::value_size(item_type) {
+            Some(size) => self.skip(len.saturating_mul(size)),
+            None => (0..len).try_for_each(|_| self.skip_value(item_type)),
+        }
+    }
+
+    fn value(&mut self, value_type: u32) -> Result<MetaValue> {
+        Ok(match value_type {
+            0 => MetaValue::UInt(u8::from_le_bytes(self.bytes()?) as u64),
+            1 => MetaValue::Int(i8::from_le_bytes(self.bytes()?) as i64),
+            2 => MetaValue::UInt(u16::from_le_bytes(self.bytes()?) as u64),
+            3 => MetaValue::Int(i16::from_le_bytes(self.bytes()?) as i64),
+            4 => MetaValue::UInt(self.u32()? as u64),
+            5 => MetaValue::Int(i32::from_le_bytes(self.bytes()?) as i64),
+            6 => MetaValue::Float(f32::from_le_bytes(self.bytes()?) as f64),
+            7 => MetaValue::Bool(self.bytes::<1>()?[0] != 0),
+            8 => MetaValue::String(self.string()?),
+            9 => {
+                let item_type = self.u32()?;
+                let len = self.array_len()?;
+                if len > MAX_KEPT_ARRAY_LEN {
+                    self.skip_items(item_type, len)?;
+                    return Ok(MetaValue::ArrayLen { len });
+                }
+                let items = (0..len)
+                    .map(|_| self.value(item_type))
+                    .collect::<Result<_>>()?;
+                MetaValue::Array(items)
+            }
+            10 => MetaValue::UInt(self.u64()?),
+            11 => MetaValue::Int(i64::from_le_bytes(self.bytes()?)),
+            12 => MetaValue::Float(f64::from_le_bytes(self.bytes()?)),
+            _ => bail!("unknown GGUF value type {}", value_type),
+        })
+    }
+
+    /// The vocab entries at `ids`, reading the array that starts at `start` but only keeping
+    /// the strings asked for
+    fn vocab_entries(&mut self, start: u64, ids: &[u64]) -> Result<HashMap<u64, String>> {
+        let mut entries = HashMap::new();
+        let Some(last) = ids.iter().max() else {
+            return Ok(entries);
+        };
+        self.reader.seek(SeekFrom::Start(start))?;
+        self.position = start;
+        for id in 0..=*last {
+            if ids.contains(&id) {
+                entries.insert(id, self.string()?);
+            } else {
+                self.skip_string()?;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Read the metadata and tensor infos of a GGUF file without loading any tensors
+/// # Errors
+/// If the file can't be opened or isn't a GGUF file
+pub fn read_metadata(path: &str) -> Result<GgufMetadata> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    parse(file, len).map_err(|e| anyhow!("{}: {}", path, e))
+}
+
+fn parse<R: Read + Seek>(reader: R, len: u64) -> Result<GgufMetadata> {
+    let mut reader = GgufReader {
+        reader: BufReader::new(reader),
+        version: 0,
+        position: 0,
+        len,
+    };
+    if &reader.bytes::<4>()? != GGUF_MAGIC {
+        bail!("not a GGUF file");
+    }
+    reader.version = reader.u32()?;
+    let tensor_count = reader.count()?;
+    let kv_count = reader.count()?;
+    let mut values = HashMap::new();
+    // Where the vocab's strings start and how many there are
+    let mut vocab = None;
+    for _ in 0..kv_count {
+        let key = reader.string()?;
+        let value_type = reader.u32()?;
+        // Only the special tokens' text is wanted from the vocab, skip it and come back later
+        if key == "tokenizer.ggml.tokens" && value_type == 9 {
+            let item_type = reader.u32()?;
+            let len = reader.array_len()?;
+            if item_type == 8 {
+                vocab = Some((reader.position, len));
+            }
+            reader.skip_items(item_type, len)?;
+            values.insert(key, MetaValue::ArrayLen { len });
+            continue;
+        }
+        values.insert(key, reader.value(value_type)?);
+    }
+
+    let mut parameter_count = 0;
+    let mut tensor_types = BTreeMap::new();
+    for _ in 0..tensor_count {
+        reader.skip_string()?;
+        let n_dims = reader.u32()?;
+        let mut elements: u64 = 1;
+        for _ in 0..n_dims {
+            elements = elements.saturating_mul(reader.count()?);
+        }
+        let tensor_type = reader.u32()?;
+        let _offset = reader.u64()?;
+        // Saturating, a corrupt header shouldn't be able to overflow the totals
+        parameter_count = u64::saturating_add(parameter_count, elements);
+        let count = tensor_types
+            .entry(tensor_type_name(tensor_type))
+            .or_insert(0);
+        *count = u64::saturating_add(*count, elements);
+    }
+
+    let special_ids: BTreeMap<String, u64> = values
+        .iter()
+        .filter_map(|(key, value)| {
+            let role = key
+                .strip_prefix("tokenizer.ggml.")?
+                .strip_suffix("_token_id")?;
+            Some((role.to_string(), value.as_u64()?))
+        })
+        .collect();
+    let texts = match vocab {
+        Some((start, len)) => {
+            let ids: Vec<u64> = special_ids
+                .values()
+                .copied()
+                .filter(|id| *id < len)
+                .collect();
+            reader.vocab_entries(start, &ids)?
+        }
+        None => HashMap::new(),
+    };
+    let special_tokens = special_ids
+        .into_iter()
+        .map(|(role, id)| {
+            let text = texts.get(&id).cloned();
+            (role, SpecialToken { id, text })
+        })
+        .collect();
+    Ok(GgufMetadata {
+        version: reader.version,
+        values,
+        tensor_count,
+        parameter_count,
+        tensor_types,
+        special_tokens,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Builds a version 3 GGUF header in memory
+    #[derive(Default)]
+    struct Writer {
+        kv: Vec<u8>,
+        kv_count: u64,
+        tensors: Vec<u8>,
+        tensor_count: u64,
+    }
+
+    fn string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend((s.len() as u64).to_le_bytes());
+        buf.extend(s.as_bytes());
+    }
+
+    impl Writer {
+        fn key(&mut self, key: &str, value_type: u32) -> &mut Vec<u8> {
+            self.kv_count += 1;
+            string(&mut self.kv, key);
+            self.kv.extend(value_type.to_le_bytes());
+            &mut self.kv
+        }
+
+        fn u32(&mut self, key: &str, value: u32) {
+            self.key(key, 4).extend(value.to_le_bytes());
+        }
+
+        fn string(&mut self, key: &str, value: &str) {
+            string(self.key(key, 8), value);
+        }
+
+        fn strings(&mut self, key: &str, values: &[&str]) {
+            let buf = self.key(key, 9);
+            buf.extend(8u32.to_le_bytes());
+            buf.extend((values.len() as u64).to_le_bytes());
+            for value in values {
+                string(buf, value);
+            }
+        }
+
+        fn tensor(&mut self, name: &str, dims: &[u64], tensor_type: u32) {
+            self.tensor_count += 1;
+            string(&mut self.tensors, name);
+            self.tensors.extend((dims.len() as u32).to_le_bytes());
+            for dim in dims {
+                self.tensors.extend(dim.to_le_bytes());
+            }
+            self.tensors.extend(tensor_type.to_le_bytes());
+            self.tensors.extend(0u64.to_le_bytes());
+        }
+
+        fn finish(&self) -> Vec<u8> {
+            let mut buf = GGUF_MAGIC.to_vec();
+            buf.extend(3u32.to_le_bytes());
+            buf.extend(self.tensor_count.to_le_bytes());
+            buf.extend(self.kv_count.to_le_bytes());
+            buf.extend(&self.kv);
+            buf.extend(&self.tensors);
+            buf
+        }
+    }
+
+    fn read(bytes: Vec<u8>) -> Result<GgufMetadata> {
+        let len = bytes.len() as u64;
+        parse(Cursor::new(bytes), len)
+    }
+
+    #[test]
+    fn reads_values_and_tensor_infos() {
+        let mut w = Writer::default();
+        w.string("general.architecture", "llama");
+        w.u32("llama.context_length", 4096);
+        w.strings("general.tags", &["a", "b"]);
+        w.tensor("token_embd.weight", &[4, 8], 12);
+        w.tensor("output.weight", &[4, 2], 0);
+        let metadata = read(w.finish()).unwrap();
+        assert_eq!(metadata.version, 3);
+        assert_eq!(metadata.architecture(), Some("llama"));
+        assert_eq!(metadata.context_length(), Some(4096));
+        assert_eq!(
+            metadata.get("general.tags"),
+            Some(&MetaValue::Array(vec![
+                MetaValue::String("a".to_string()),
+                MetaValue::String("b".to_string()),
+            ]))
+        );
+        assert_eq!(metadata.tensor_count, 2);
+        assert_eq!(metadata.parameter_count, 40);
+        assert_eq!(metadata.quantization().as_deref(), Some("Q4_K"));
+    }
+
+    #[test]
+    fn big_arrays_keep_only_their_length() {
+        let mut w = Writer::default();
+        let merges: Vec<String> = (0..100).map(|i| format!("m{}", i)).collect();
+        let merges: Vec<&str> = merges.iter().map(String::as_str).collect();
+        w.strings("tokenizer.ggml.merges", &merges);
+        w.u32("general.alignment", 32);
+        let metadata = read(w.finish()).unwrap();
+        assert_eq!(
+            metadata.get("tokenizer.ggml.merges"),
+            Some(&MetaValue::ArrayLen { len: 100 })
+        );
+        assert_eq!(
+            metadata.get("general.alignment"),
+            Some(&MetaValue::UInt(32))
+        );
+    }
+
+    #[test]
+    fn special_tokens_take_their_text_from_the_vocab() {
+        let mut w = Writer::default();
+        w.strings("tokenizer.ggml.tokens", &["<unk>", "<s>", "</s>", "hi"]);
+        w.u32("tokenizer.ggml.bos_token_id", 1);
+        w.u32("tokenizer.ggml.eos_token_id", 2);
+        w.u32("tokenizer.ggml.padding_token_id", 9);
+        let metadata = read(w.finish()).unwrap();
+        assert_eq!(metadata.vocab_size(), Some(4));
+        let text = |role: &str| metadata.special_tokens[role].text.clone();
+        assert_eq!(text("bos").as_deref(), Some("<s>"));
+        assert_eq!(text("eos").as_deref(), Some("</s>"));
+        assert_eq!(text("padding"), None);
+    }
+
+    #[test]
+    fn lengths_past_the_end_of_the_file_are_errors() {
+        let mut w = Writer::default();
+        let buf = w.key("general.name", 8);
+        buf.extend(u64::MAX.to_le_bytes());
+        assert!(read(w.finish()).is_err());
+
+        let mut w = Writer::default();
+        let buf = w.key("tokenizer.ggml.tokens", 9);
+        buf.extend(8u32.to_le_bytes());
+        buf.extend((1u64 << 40).to_le_bytes());
+        assert!(read(w.finish()).is_err());
+    }
+
+    #[test]
+    fn huge_tensor_dims_saturate() {
+        let mut w = Writer::default();
+        w.tensor("a", &[u64::MAX, 2], 0);
+        w.tensor("b", &[u64::MAX], 0);
+        let metadata = read(w.finish()).unwrap();
+        assert_eq!(metadata.parameter_count, u64::MAX);
+        assert_eq!(metadata.tensor_types["F32"], u64::MAX);
+    }
+
+    #[test]
+    fn big_fixed_size_arrays_are_skipped() {
+        let mut w = Writer::default();
+        let buf = w.key("tokenizer.ggml.token_type", 9);
+        buf.extend(5u32.to_le_bytes());
+        buf.extend(100u64.to_le_bytes());
+        buf.extend([0u8; 400]);
+        w.u32("general.alignment", 32);
+        let metadata = read(w.finish()).unwrap();
+        assert_eq!(
+            metadata.get("tokenizer.ggml.token_type"),
+            Some(&MetaValue::ArrayLen { len: 100 })
+        );
+        assert_eq!(
+            metadata.get("general.alignment"),
+            Some(&MetaValue::UInt(32))
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(read(b"GGML\x03\x00\x00\x00".to_vec()).is_err());
+        assert!(read(Vec::new()).is_err());
+    }
+}
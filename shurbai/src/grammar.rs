@@ -1,6 +1,35 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
 
+use anyhow::{anyhow, Result};
 use llama_cpp_2::grammar::LlamaGrammar;
+use serde::{Deserialize, Serialize};
+
+use crate::lru::Lru;
+
+/// How many compiled grammars are kept around, the least recently used goes first
+const GRAMMAR_CACHE_SIZE: usize = 64;
+
+/// A compiled grammar and the source it came from, to rule out hash collisions
+struct CachedGrammar {
+    source: String,
+    grammar: LlamaGrammar,
+}
+
+// `LlamaGrammar` owns its llama.cpp grammar through a raw pointer, which is all that keeps it
+// from being `Send`. On the llama.cpp side a `llama_grammar` is plain heap data: its rules, the
+// parse stacks (pointers into those same rules) and the pending UTF-8 state, with nothing global
+// or thread local attached. Freeing it on another thread is an ordinary `delete`
+// The cached copy is never sampled with, it's only cloned while the cache lock is held. Cloning
+// goes through `llama_grammar_copy`, which copies the rules and points the new stacks at the
+// copy's own rules, so what a generation thread gets back shares no memory with the cache
+unsafe impl Send for CachedGrammar {}
+
+/// Shared by the request handlers, which validate, and the generation threads, which compile
+static GRAMMAR_CACHE: LazyLock<Mutex<Lru<u64, CachedGrammar>>> =
+    LazyLock::new(|| Mutex::new(Lru::new(GRAMMAR_CACHE_SIZE)));
 
 pub const JSON_GRAMMAR: &str = r#"# This is the same as json.gbnf but we restrict whitespaces at the end of the root array
 # Useful for generating JSON arrays
@@ -37,7 +66,171 @@ number ::= ("-"? ([0-9] | [1-9] [0-9]*)) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? ws
 # Optional space: by convention, applied in this grammar after literal chars when allowed
 ws ::= ([ \t\n] ws)?"#;
 
-/// Load the statically defined JSON grammar
-pub fn load_grammar() -> LlamaGrammar {
-    LlamaGrammar::from_str(JSON_GRAMMAR).expect("Failed to load grammar")
+pub const JSON_OBJECT_GRAMMAR: &str = r#"# This is the same as json.gbnf but rooted at an object with no trailing whitespace
+# so the only thing left to sample once the root object closes is EOS
+
+root   ::= "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}"
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F]) # escapes
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]*)) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? ws
+
+# Optional space: by convention, applied in this grammar after literal chars when allowed
+ws ::= ([ \t\n] ws)?"#;
+
+/// What the root value of JSON mode output is
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonRoot {
+    Object,
+    Array,
+}
+
+/// The built in JSON grammar for the given root
+pub fn json_grammar(root: JsonRoot) -> &'static str {
+    match root {
+        JsonRoot::Object => JSON_OBJECT_GRAMMAR,
+        JsonRoot::Array => JSON_GRAMMAR,
+    }
+}
+
+fn hash_grammar(source: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Compile a GBNF grammar, reusing a cached copy when the same source was compiled before
+/// # Errors
+/// If the grammar fails to parse, the error holds the parser message
+pub fn compile_grammar(source: &str) -> Result<LlamaGrammar> {
+    let key = hash_grammar(source);
+    if let Some(cached) = GRAMMAR_CACHE.lock().unwrap().get(&key) {
+        if cached.source == source {
+            return Ok(cached.grammar.clone());
+        }
+    }
+    // Parsed without the lock, a big grammar shouldn't hold up everyone else's lookups
+    let grammar = LlamaGrammar::from_str(source).map_err(|e| anyhow!("invalid grammar: {}", e))?;
+    GRAMMAR_CACHE.lock().unwrap().insert(
+        key,
+        CachedGrammar {
+            source: source.to_string(),
+            grammar: grammar.clone(),
+        },
+    );
+    Ok(grammar)
+}
+
+/// Check that a GBNF grammar parses, so requests can be rejected before generation starts
+pub fn validate_grammar(source: &str) -> Result<()> {
+    compile_grammar(source).map(|_| ())
+}
+
+/// Follows JSON text as it streams in and spots when the root object or array closes
+#[derive(Debug, Default)]
+pub struct JsonCloseTracker {
+    depth: i32,
+    started: bool,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonCloseTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next chunk of output, returns true once the root value has closed
+    pub fn feed(&mut self, text: &str) -> bool {
+        for c in text.chars() {
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if c == '\\' {
+                    self.escaped = true;
+                } else if c == '"' {
+                    self.in_string = false;
+                }
+                continue;
+            }
+            match c {
+                '"' => self.in_string = true,
+                '{' | '[' => {
+                    self.depth += 1;
+                    self.started = true;
+                }
+                '}' | ']' => self.depth -= 1,
+                _ => {}
+            }
+            if self.started && self.depth <= 0 {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_grammar_picks_the_root() {
+        assert!(json_grammar(JsonRoot::Object).contains("root   ::= \"{\""));
+        assert!(json_grammar(JsonRoot::Array).contains("root   ::= arr"));
+    }
+
+    #[test]
+    fn compiled_grammars_are_cached_by_source() {
+        let source = "root ::= \"compiled_grammars_are_cached_by_source\"";
+        compile_grammar(source).unwrap();
+        let mut cache = GRAMMAR_CACHE.lock().unwrap();
+        let cached = cache
+            .get(&hash_grammar(source))
+            .expect("grammar was cached");
+        assert_eq!(cached.source, source);
+    }
+
+    #[test]
+    fn close_tracker_spots_the_root_closing() {
+        let mut tracker = JsonCloseTracker::new();
+        assert!(!tracker.feed("{\"a\": [1, 2"));
+        assert!(!tracker.feed("], \"b\": {}"));
+        assert!(tracker.feed("}"));
+    }
+
+    #[test]
+    fn close_tracker_skips_brackets_in_strings() {
+        let mut tracker = JsonCloseTracker::new();
+        assert!(!tracker.feed("{\"a\": \"}]\\\"}\""));
+        assert!(tracker.feed(" }"));
+    }
+
+    #[test]
+    fn close_tracker_waits_for_the_root_to_open() {
+        let mut tracker = JsonCloseTracker::new();
+        assert!(!tracker.feed("  \n"));
+        assert!(tracker.feed("[]"));
+    }
 }
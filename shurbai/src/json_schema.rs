@@ -0,0 +1,811 @@
+//! Turn a JSON Schema into a GBNF grammar so structured output can be forced at sample time
+//! Supports objects (properties, required), enums, const, arrays, anyOf/oneOf, `$ref`,
+//! string formats, lengths and patterns, and numeric bounds. Bounds that GBNF can't express (like the
+//! exact numeric range) are left to `validate`, which should be run on the finished output.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+
+use crate::regex_grammar::pattern_to_gbnf;
+
+/// Bounded repetitions are written out in full, spans wider than this are refused to keep
+/// the grammar small
+const MAX_EXPANDED_REPEAT: usize = 64;
+
+/// Keywords that only apply to strings, so a schema without a `type` that uses them is a string
+const STRING_KEYWORDS: &[&str] = &["pattern", "minLength", "maxLength"];
+
+/// Built in rules, with the other built in rules each one needs
+const PRIMITIVE_RULES: &[(&str, &str, &[&str])] = &[
+    ("space", r#"" "?"#, &[]),
+    ("boolean", r#"("true" | "false")"#, &[]),
+    ("null", r#""null""#, &[]),
+    ("integer", r#""-"? ("0" | [1-9] [0-9]*)"#, &[]),
+    ("uinteger", r#"("0" | [1-9] [0-9]*)"#, &[]),
+    (
+        "number",
+        r#""-"? ("0" | [1-9] [0-9]*) ("." [0-9]+)? ([eE] [-+]? [0-9]+)?"#,
+        &[],
+    ),
+    (
+        "unumber",
+        r#"("0" | [1-9] [0-9]*) ("." [0-9]+)? ([eE] [-+]? [0-9]+)?"#,
+        &[],
+    ),
+    (
+        "char",
+        r#"[^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F])"#,
+        &[],
+    ),
+    ("string", r#""\"" char* "\"""#, &["char"]),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+        &["object", "array", "string", "number", "boolean", "null"],
+    ),
+    (
+        "object",
+        r#""{" space (string ":" space value ("," space string ":" space value)*)? space "}""#,
+        &["space", "string", "value"],
+    ),
+    (
+        "array",
+        r#""[" space (value ("," space value)*)? space "]""#,
+        &["space", "value"],
+    ),
+    (
+        "date",
+        r#"[0-9] [0-9] [0-9] [0-9] "-" ("0" [1-9] | "1" [0-2]) "-" ("0" [1-9] | [1-2] [0-9] | "3" [0-1])"#,
+        &[],
+    ),
+    (
+        "time",
+        r#"([01] [0-9] | "2" [0-3]) ":" [0-5] [0-9] ":" [0-5] [0-9] ("." [0-9] [0-9] [0-9])? ("Z" | ("+" | "-") ([01] [0-9] | "2" [0-3]) ":" [0-5] [0-9])"#,
+        &[],
+    ),
+    ("date-time", r#"date "T" time"#, &["date", "time"]),
+    (
+        "uuid",
+        r#"hex hex hex hex hex hex hex hex "-" hex hex hex hex "-" hex hex hex hex "-" hex hex hex hex "-" hex hex hex hex hex hex hex hex hex hex hex hex"#,
+        &["hex"],
+    ),
+    ("hex", r#"[0-9a-fA-F]"#, &[]),
+    (
+        "email",
+        r#"[a-zA-Z0-9._%+-]+ "@" [a-zA-Z0-9-]+ ("." [a-zA-Z0-9-]+)+"#,
+        &[],
+    ),
+];
+
+/// Convert a JSON Schema into a GBNF grammar rooted at `root`
+/// # Errors
+/// If the schema uses a keyword or `$ref` that can't be compiled
+pub fn schema_to_grammar(schema: &Value) -> Result<String> {
+    schema_rules(schema, "root")
+}
+
+/// Convert a JSON Schema into GBNF rules with the top rule called `name`
+/// Useful for embedding a schema inside a bigger hand written grammar
+pub fn schema_rules(schema: &Value, name: &str) -> Result<String> {
+    let mut converter = SchemaConverter::new(schema);
+    let top = converter.visit(schema, name)?;
+    if top != name {
+        converter.rules.insert(name.to_string(), top);
+    }
+    Ok(converter.format_grammar())
+}
+
+/// Escape text so it can sit inside a GBNF string literal
+pub fn gbnf_literal(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Rule names can only hold letters, digits and dashes
+fn rule_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+/// Repeat `item` between `min` and `max` times with `sep` between each copy
+/// # Errors
+/// If `max` is below `min`, or more than `MAX_EXPANDED_REPEAT` above it
+pub(crate) fn repeat(item: &str, min: usize, max: Option<usize>, sep: &str) -> Result<String> {
+    if let Some(max) = max {
+        if max < min {
+            bail!("repetition bound {} is below the minimum {}", max, min);
+        }
+        if max - min > MAX_EXPANDED_REPEAT {
+            bail!(
+                "repetition range {}..{} is too wide, at most {} optional repeats are supported",
+                min,
+                max,
+                MAX_EXPANDED_REPEAT
+            );
+        }
+    }
+    let with_sep = if sep.is_empty() {
+        item.to_string()
+    } else {
+        format!("{} {}", sep, item)
+    };
+    let mut parts = Vec::new();
+    for i in 0..min {
+        parts.push(if i == 0 {
+            item.to_string()
+        } else {
+            with_sep.clone()
+        });
+    }
+    match max {
+        None if min == 0 => parts.push(format!("({} ({})*)?", item, with_sep)),
+        None => parts.push(format!("({})*", with_sep)),
+        Some(max) => {
+            let mut tail = String::new();
+            for i in (min..max).rev() {
+                let elem = if i == 0 { item } else { &with_sep };
+                tail = if tail.is_empty() {
+                    format!("({})?", elem)
+                } else {
+                    format!("({} {})?", elem, tail)
+                };
+            }
+            if !tail.is_empty() {
+                parts.push(tail);
+            }
+        }
+    }
+    Ok(parts.join(" "))
+}
+
+fn get_usize(schema: &Map<String, Value>, key: &str) -> Option<usize> {
+    schema.get(key).and_then(Value::as_u64).map(|v| v as usize)
+}
+
+fn resolve_ref<'a>(root: &'a Value, reference: &str) -> Result<&'a Value> {
+    let pointer = reference
+        .strip_prefix('#')
+        .ok_or_else(|| anyhow!("only local $ref values are supported, got {}", reference))?;
+    root.pointer(pointer)
+        .ok_or_else(|| anyhow!("could not resolve $ref {}", reference))
+}
+
+struct SchemaConverter<'a> {
+    root: &'a Value,
+    rules: BTreeMap<String, String>,
+    refs: HashMap<String, String>,
+}
+
+impl<'a> SchemaConverter<'a> {
+    fn new(root: &'a Value) -> Self {
+        SchemaConverter {
+            root,
+            rules: BTreeMap::new(),
+            refs: HashMap::new(),
+        }
+    }
+
+    fn format_grammar(&self) -> String {
+        self.rules
+            .iter()
+            .map(|(name, body)| format!("{} ::= {}", name, body))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn primitive(&mut self, name: &str) -> String {
+        if !self.rules.contains_key(name) {
+            let (_, body, deps) = PRIMITIVE_RULES
+                .iter()
+                .find(|(n, _, _)| *n == name)
+                .expect("unknown primitive rule");
+            self.rules.insert(name.to_string(), body.to_string());
+            for dep in deps.iter() {
+                self.primitive(dep);
+            }
+        }
+        name.to_string()
+    }
+
+    fn unique_name(&self, name: &str) -> String {
+        let base = rule_name(name);
+        let taken = |n: &String| self.rules.contains_key(n) || self.refs.values().any(|r| r == n);
+        if !taken(&base) {
+            return base;
+        }
+        (1..)
+            .map(|i| format!("{}{}", base, i))
+            .find(|n| !taken(n))
+            .unwrap()
+    }
+
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let name = rule_name(name);
+        match self.rules.get(&name) {
+            Some(existing) if *existing == body => name,
+            Some(_) => {
+                let name = self.unique_name(&name);
+                self.rules.insert(name.clone(), body);
+                name
+            }
+            None => {
+                self.rules.insert(name.clone(), body);
+                name
+            }
+        }
+    }
+
+    /// Add a rule for `schema` and return the name to refer to it by
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            Value::Bool(false) => bail!("a false schema can never be satisfied"),
+            Value::Object(schema) => schema,
+            _ => bail!("schema at {} must be an object", name),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            if let Some(existing) = self.refs.get(reference) {
+                return Ok(existing.clone());
+            }
+            let target = resolve_ref(self.root, reference)?;
+            let ref_name = self.unique_name(&format!(
+                "ref-{}",
+                reference.rsplit('/').next().unwrap_or("")
+            ));
+            self.refs.insert(reference.to_string(), ref_name.clone());
+            let target_name = self.visit(target, &ref_name)?;
+            if target_name != ref_name {
+                // The target resolved to a shared rule, alias it so recursive uses still resolve
+                self.rules.insert(ref_name.clone(), target_name);
+            }
+            return Ok(ref_name);
+        }
+
+        if let Some(alternatives) = schema
+            .get("anyOf")
+            .or_else(|| schema.get("oneOf"))
+            .and_then(Value::as_array)
+        {
+            let mut names = Vec::new();
+            for (i, alternative) in alternatives.iter().enumerate() {
+                names.push(self.visit(alternative, &format!("{}-{}", name, i))?);
+            }
+            return Ok(self.add_rule(name, names.join(" | ")));
+        }
+        if schema.contains_key("allOf") {
+            bail!("allOf is not supported in structured output schemas");
+        }
+
+        if let Some(value) = schema.get("const") {
+            return Ok(self.add_rule(name, gbnf_literal(&value.to_string())));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            let body = values
+                .iter()
+                .map(|v| gbnf_literal(&v.to_string()))
+                .collect::<Vec<_>>()
+                .join(" | ");
+            return Ok(self.add_rule(name, format!("({})", body)));
+        }
+
+        match schema.get("type") {
+            Some(Value::Array(types)) => {
+                let mut names = Vec::new();
+                for t in types {
+                    let mut single = schema.clone();
+                    single.insert("type".to_string(), t.clone());
+                    let alt_name = format!("{}-{}", name, t.as_str().unwrap_or("value"));
+                    names.push(self.visit(&Value::Object(single), &alt_name)?);
+                }
+                Ok(self.add_rule(name, names.join(" | ")))
+            }
+            Some(Value::String(t)) => self.visit_type(t, schema, name),
+            Some(_) => bail!("invalid type in schema at {}", name),
+            None if schema.contains_key("properties") => self.visit_type("object", schema, name),
+            None if schema.contains_key("items") => self.visit_type("array", schema, name),
+            None if STRING_KEYWORDS.iter().any(|k| schema.contains_key(*k)) => {
+                self.visit_type("string", schema, name)
+            }
+            None => Ok(self.primitive("value")),
+        }
+    }
+
+    fn visit_type(&mut self, t: &str, schema: &Map<String, Value>, name: &str) -> Result<String> {
+        match t {
+            "object" => self.visit_object(schema, name),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.visit(items, &format!("{}-item", name))?,
+                    None => self.primitive("value"),
+                };
+                let space = self.primitive("space");
+                let items = repeat(
+                    &item,
+                    get_usize(schema, "minItems").unwrap_or(0),
+                    get_usize(schema, "maxItems"),
+                    &format!("\",\" {}", space),
+                )
+                .map_err(|e| anyhow!("{} at {}", e, name))?;
+                Ok(self.add_rule(name, format!("\"[\" {} {} {} \"]\"", space, items, space)))
+            }
+            "string" => self.visit_string(schema, name),
+            "integer" | "number" => {
+                // GBNF can't hold a numeric range, but we can at least keep the sign right
+                let non_negative = ["minimum", "exclusiveMinimum"]
+                    .iter()
+                    .filter_map(|k| schema.get(*k).and_then(Value::as_f64))
+                    .any(|min| min >= 0.0);
+                let primitive = match (t, non_negative) {
+                    ("integer", true) => "uinteger",
+                    ("integer", false) => "integer",
+                    (_, true) => "unumber",
+                    (_, false) => "number",
+                };
+                Ok(self.primitive(primitive))
+            }
+            "boolean" => Ok(self.primitive("boolean")),
+            "null" => Ok(self.primitive("null")),
+            _ => bail!("unsupported type {} in schema at {}", t, name),
+        }
+    }
+
+    fn visit_string(&mut self, schema: &Map<String, Value>, name: &str) -> Result<String> {
+        if let Some(format) = schema.get("format").and_then(Value::as_str) {
+            if matches!(format, "date" | "time" | "date-time" | "uuid" | "email") {
+                let inner = self.primitive(format);
+                return Ok(self.add_rule(name, format!("\"\\\"\" {} \"\\\"\"", inner)));
+            }
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            let any_char = self.primitive("char");
+            let inner = pattern_to_gbnf(pattern, &any_char)?;
+            return Ok(self.add_rule(name, format!("\"\\\"\" {} \"\\\"\"", inner)));
+        }
+        let min = get_usize(schema, "minLength").unwrap_or(0);
+        let max = get_usize(schema, "maxLength");
+        if min == 0 && max.is_none() {
+            return Ok(self.primitive("string"));
+        }
+        let char_rule = self.primitive("char");
+        let chars = repeat(&char_rule, min, max, "").map_err(|e| anyhow!("{} at {}", e, name))?;
+        Ok(self.add_rule(name, format!("\"\\\"\" {} \"\\\"\"", chars)))
+    }
+
+    fn visit_object(&mut self, schema: &Map<String, Value>, name: &str) -> Result<String> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Ok(self.primitive("object"));
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|r| r.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let space = self.primitive("space");
+
+        // Required keys without a property schema can hold any value, they come after the
+        // declared properties, which keep their declaration order (serde_json `preserve_order`)
+        let extra_required = required
+            .iter()
+            .filter(|k| !properties.contains_key(**k))
+            .map(|k| (k.to_string(), Value::Bool(true)));
+        let properties: Vec<(String, Value)> = properties
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .chain(extra_required)
+            .collect();
+
+        let mut kv_rules = HashMap::new();
+        for (key, prop_schema) in &properties {
+            let value = self.visit(prop_schema, &format!("{}-{}", name, key))?;
+            let kv = self.add_rule(
+                &format!("{}-{}-kv", name, key),
+                format!(
+                    "{} {} \":\" {} {}",
+                    gbnf_literal(&Value::String(key.clone()).to_string()),
+                    space,
+                    space,
+                    value
+                ),
+            );
+            kv_rules.insert(key.as_str(), kv);
+        }
+        let required_kvs: Vec<&String> = properties
+            .iter()
+            .filter(|(k, _)| required.contains(&k.as_str()))
+            .map(|(k, _)| &kv_rules[k.as_str()])
+            .collect();
+        let optional_keys: Vec<&str> = properties
+            .iter()
+            .filter(|(k, _)| !required.contains(&k.as_str()))
+            .map(|(k, _)| k.as_str())
+            .collect();
+
+        let sep = format!("\",\" {}", space);
+        let mut body = format!("\"{{\" {}", space);
+        body.push(' ');
+        body.push_str(
+            &required_kvs
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join(&format!(" {} ", sep)),
+        );
+        if !optional_keys.is_empty() {
+            // Optional properties keep their declared order, any of them can be skipped
+            let mut alternatives = Vec::new();
+            for i in 0..optional_keys.len() {
+                alternatives.push(self.optional_chain(name, &optional_keys[i..], &kv_rules, &sep));
+            }
+            let alternatives = alternatives.join(" | ");
+            if required_kvs.is_empty() {
+                body.push_str(&format!(" ({})?", alternatives));
+            } else {
+                body.push_str(&format!(" ({} ({}))?", sep, alternatives));
+            }
+        }
+        body.push_str(&format!(" {} \"}}\"", space));
+        Ok(self.add_rule(name, body))
+    }
+
+    /// The first key is written out, every later one is optional and comma separated
+    fn optional_chain(
+        &mut self,
+        name: &str,
+        keys: &[&str],
+        kv_rules: &HashMap<&str, String>,
+        sep: &str,
+    ) -> String {
+        let mut chain = kv_rules[keys[0]].clone();
+        if keys.len() > 1 {
+            let rest = self.optional_rest(name, &keys[1..], kv_rules, sep);
+            chain = format!("{} {}", chain, rest);
+        }
+        chain
+    }
+
+    fn optional_rest(
+        &mut self,
+        name: &str,
+        keys: &[&str],
+        kv_rules: &HashMap<&str, String>,
+        sep: &str,
+    ) -> String {
+        let mut body = format!("({} {})?", sep, kv_rules[keys[0]]);
+        if keys.len() > 1 {
+            let rest = self.optional_rest(name, &keys[1..], kv_rules, sep);
+            body = format!("{} {}", body, rest);
+        }
+        self.add_rule(&format!("{}-{}-rest", name, keys[0]), body)
+    }
+}
+
+/// Check a value against a schema, covering the same keywords `schema_to_grammar` handles
+/// # Errors
+/// Describes the first part of the value that doesn't match
+pub fn validate(schema: &Value, value: &Value) -> Result<()> {
+    check(schema, schema, value, "$")
+}
+
+fn check(root: &Value, schema: &Value, value: &Value, path: &str) -> Result<()> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => bail!("{} is not allowed", path),
+        Value::Object(schema) => schema,
+        _ => bail!("schema at {} must be an object", path),
+    };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        return check(root, resolve_ref(root, reference)?, value, path);
+    }
+    if let Some(alternatives) = schema
+        .get("anyOf")
+        .or_else(|| schema.get("oneOf"))
+        .and_then(Value::as_array)
+    {
+        if !alternatives
+            .iter()
+            .any(|alt| check(root, alt, value, path).is_ok())
+        {
+            bail!("{} does not match any of the allowed schemas", path);
+        }
+        return Ok(());
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            bail!("{} must be {}", path, expected);
+        }
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        if !values.contains(value) {
+            bail!("{} must be one of {}", path, Value::Array(values.clone()));
+        }
+    }
+    match schema.get("type") {
+        Some(Value::String(t)) => check_type(root, t, schema, value, path),
+        Some(Value::Array(types)) => {
+            let matched = types
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|t| check_type(root, t, schema, value, path).is_ok());
+            if matched {
+                Ok(())
+            } else {
+                bail!(
+                    "{} does not match any of the types {}",
+                    path,
+                    Value::Array(types.clone())
+                )
+            }
+        }
+        _ if value.is_object() && schema.contains_key("properties") => {
+            check_type(root, "object", schema, value, path)
+        }
+        _ if value.is_array() && schema.contains_key("items") => {
+            check_type(root, "array", schema, value, path)
+        }
+        _ if value.is_string() && STRING_KEYWORDS.iter().any(|k| schema.contains_key(*k)) => {
+            check_type(root, "string", schema, value, path)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_type(
+    root: &Value,
+    t: &str,
+    schema: &Map<String, Value>,
+    value: &Value,
+    path: &str,
+) -> Result<()> {
+    match (t, value) {
+        ("object", Value::Object(object)) => {
+            if let Some(required) = schema.get("required").and_then(Value::as_array) {
+                for key in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(key) {
+                        bail!("{} is missing the required property {}", path, key);
+                    }
+                }
+            }
+            if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+                for (key, v) in object {
+                    let child = format!("{}.{}", path, key);
+                    match properties.get(key) {
+                        Some(prop_schema) => check(root, prop_schema, v, &child)?,
+                        None => match schema.get("additionalProperties") {
+                            Some(Value::Bool(false)) => bail!("{} is not allowed", child),
+                            Some(extra) => check(root, extra, v, &child)?,
+                            None => {}
+                        },
+                    }
+                }
+            }
+            Ok(())
+        }
+        ("array", Value::Array(items)) => {
+            if let Some(min) = get_usize(schema, "minItems") {
+                if items.len() < min {
+                    bail!("{} needs at least {} items", path, min);
+                }
+            }
+            if let Some(max) = get_usize(schema, "maxItems") {
+                if items.len() > max {
+                    bail!("{} can have at most {} items", path, max);
+                }
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(root, item_schema, item, &format!("{}[{}]", path, i))?;
+                }
+            }
+            Ok(())
+        }
+        ("string", Value::String(s)) => {
+            let len = s.chars().count();
+            if let Some(min) = get_usize(schema, "minLength") {
+                if len < min {
+                    bail!("{} must be at least {} characters", path, min);
+                }
+            }
+            if let Some(max) = get_usize(schema, "maxLength") {
+                if len > max {
+                    bail!("{} must be at most {} characters", path, max);
+                }
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                let regex = regex::Regex::new(pattern)
+                    .map_err(|e| anyhow!("invalid pattern at {}: {}", path, e))?;
+                if !regex.is_match(s) {
+                    bail!("{} must match the pattern {}", path, pattern);
+                }
+            }
+            Ok(())
+        }
+        ("integer", Value::Number(n)) if n.is_i64() || n.is_u64() => check_bounds(schema, n, path),
+        ("integer", Value::Number(n)) if n.as_f64().is_some_and(|f| f.fract() == 0.0) => {
+            check_bounds(schema, n, path)
+        }
+        ("number", Value::Number(n)) => check_bounds(schema, n, path),
+        ("boolean", Value::Bool(_)) | ("null", Value::Null) => Ok(()),
+        _ => bail!("{} must be of type {}", path, t),
+    }
+}
+
+fn check_bounds(schema: &Map<String, Value>, n: &serde_json::Number, path: &str) -> Result<()> {
+    let n = n.as_f64().unwrap_or_default();
+    let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+    if let Some(min) = bound("minimum") {
+        if n < min {
+            bail!("{} must be >= {}", path, min);
+        }
+    }
+    if let Some(max) = bound("maximum") {
+        if n > max {
+            bail!("{} must be <= {}", path, max);
+        }
+    }
+    if let Some(min) = bound("exclusiveMinimum") {
+        if n <= min {
+            bail!("{} must be > {}", path, min);
+        }
+    }
+    if let Some(max) = bound("exclusiveMaximum") {
+        if n >= max {
+            bail!("{} must be < {}", path, max);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule<'a>(grammar: &'a str, name: &str) -> &'a str {
+        let prefix = format!("{} ::= ", name);
+        grammar
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix.as_str()))
+            .unwrap_or_else(|| panic!("no rule {} in\n{}", name, grammar))
+    }
+
+    #[test]
+    fn repeat_expands_bounded_ranges() {
+        assert_eq!(repeat("x", 1, Some(3), "").unwrap(), "x (x (x)?)?");
+        assert_eq!(repeat("x", 2, Some(2), "").unwrap(), "x x");
+        assert_eq!(repeat("x", 0, None, "\",\"").unwrap(), "(x (\",\" x)*)?");
+        assert_eq!(repeat("x", 1, None, "").unwrap(), "x (x)*");
+    }
+
+    #[test]
+    fn repeat_rejects_bounds_it_cant_write_out() {
+        assert!(repeat("x", 0, Some(MAX_EXPANDED_REPEAT), "").is_ok());
+        assert!(repeat("x", 0, Some(MAX_EXPANDED_REPEAT + 1), "").is_err());
+        assert!(repeat("x", 3, Some(2), "").is_err());
+        let schema = json!({"type": "string", "maxLength": 500});
+        assert!(schema_to_grammar(&schema).is_err());
+    }
+
+    #[test]
+    fn properties_keep_their_declared_order() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"zeta": {"type": "string"}, "alpha": {"type": "integer"}},
+            "required": ["zeta", "alpha"]
+        });
+        let grammar = schema_to_grammar(&schema).unwrap();
+        let root = rule(&grammar, "root");
+        assert!(root.find("root-zeta-kv").unwrap() < root.find("root-alpha-kv").unwrap());
+    }
+
+    #[test]
+    fn required_keys_without_a_schema_take_any_value() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"name": {"type": "string"}},
+            "required": ["name", "id"]
+        });
+        let grammar = schema_to_grammar(&schema).unwrap();
+        assert!(rule(&grammar, "root").contains("root-id-kv"));
+        assert!(rule(&grammar, "root-id-kv").ends_with("value"));
+    }
+
+    #[test]
+    fn optional_properties_can_be_skipped() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"a": {"type": "boolean"}, "b": {"type": "null"}}
+        });
+        let grammar = schema_to_grammar(&schema).unwrap();
+        assert!(rule(&grammar, "root").contains("(root-a-kv root-b-rest | root-b-kv)?"));
+    }
+
+    #[test]
+    fn enums_and_consts_become_literals() {
+        let grammar = schema_to_grammar(&json!({"enum": ["a", 1]})).unwrap();
+        assert_eq!(rule(&grammar, "root"), r#"("\"a\"" | "1")"#);
+        let grammar = schema_to_grammar(&json!({"const": true})).unwrap();
+        assert_eq!(rule(&grammar, "root"), r#""true""#);
+    }
+
+    #[test]
+    fn recursive_refs_compile() {
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {"children": {"type": "array", "items": {"$ref": "#/$defs/node"}}}
+                }
+            }
+        });
+        let grammar = schema_to_grammar(&schema).unwrap();
+        assert_eq!(rule(&grammar, "root"), "ref-node");
+        assert!(rule(&grammar, "ref-node-children").contains("(ref-node (\",\" space ref-node)*)?"));
+    }
+
+    #[test]
+    fn unsupported_keywords_are_errors() {
+        assert!(schema_to_grammar(&json!({"allOf": []})).is_err());
+        assert!(schema_to_grammar(&json!({"type": "decimal"})).is_err());
+        assert!(schema_to_grammar(&json!({"$ref": "http://example.com/schema"})).is_err());
+    }
+
+    #[test]
+    fn validate_checks_objects() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"n": {"type": "integer", "minimum": 0}},
+            "required": ["n"],
+            "additionalProperties": false
+        });
+        assert!(validate(&schema, &json!({"n": 3})).is_ok());
+        assert!(validate(&schema, &json!({})).is_err());
+        assert!(validate(&schema, &json!({"n": -1})).is_err());
+        assert!(validate(&schema, &json!({"n": 1.5})).is_err());
+        assert!(validate(&schema, &json!({"n": 1, "m": 2})).is_err());
+    }
+
+    #[test]
+    fn validate_checks_lengths_and_alternatives() {
+        let schema = json!({
+            "anyOf": [
+                {"type": "string", "minLength": 2, "maxLength": 3},
+                {"type": "array", "maxItems": 1}
+            ]
+        });
+        assert!(validate(&schema, &json!("ab")).is_ok());
+        assert!(validate(&schema, &json!("a")).is_err());
+        assert!(validate(&schema, &json!("abcd")).is_err());
+        assert!(validate(&schema, &json!([1])).is_ok());
+        assert!(validate(&schema, &json!([1, 2])).is_err());
+        assert!(validate(&schema, &json!(null)).is_err());
+    }
+
+    #[test]
+    fn patterns_are_unanchored_json_strings() {
+        let schema = json!({"type": "string", "pattern": "[0-9]{3}"});
+        let grammar = schema_to_grammar(&schema).unwrap();
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""\"" char* ([0-9] [0-9] [0-9]) char* "\"""#
+        );
+        assert!(validate(&schema, &json!("call 555 now")).is_ok());
+        assert!(validate(&schema, &json!("55")).is_err());
+        assert!(validate(&json!({"pattern": "^a$"}), &json!("ab")).is_err());
+    }
+}
@@ -18,19 +18,33 @@ use llama_cpp_2::model::LlamaModel;
 use llama_cpp_2::model::{AddBos, Special};
 use llama_cpp_2::token::data_array::LlamaTokenDataArray;
 use llama_cpp_2::token::LlamaToken;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sampling::TokenHistory;
 
+use grammar::JsonCloseTracker;
 use std::num::NonZeroU32;
 use std::thread::sleep;
-use types::{LlamaResult, ModelConfig, ModelManager, ModelState};
+use types::{
+    GrammarConstraint, LlamaResult, LoadedLoraAdapter, LoraAdapter, LoraAdapterConfig,
+    LoraSelection, ModelConfig, ModelManager, ModelState, SamplingParams,
+};
 
-use std::collections::HashMap;
 use std::time::Duration;
 
 pub type TokenCallback = Box<dyn Fn(String, bool)>;
 
+pub mod embedding_cache;
 pub mod embeddings;
-mod grammar;
+pub mod gguf;
+pub mod grammar;
+pub mod json_schema;
+pub mod lru;
+pub mod manager;
+pub mod presets;
+pub mod regex_grammar;
+pub mod rerank;
+pub mod sampling;
 pub mod types;
 
 fn find_stops(s: Option<&Vec<String>>, t: &str) -> bool {
@@ -74,33 +88,48 @@ pub fn load_model(
     Ok(model)
 }
 
-/// Load models from a list of model definitions and create a model manager
+/// Load the LoRA adapters for a model
+/// # Arguments
+/// * `model` - The model the adapters were trained on
+/// * `adapters` - The adapters from the model definition
+/// # Returns
+/// The loaded adapters, in the order they were given
+/// # Errors
+/// If an adapter fails to load, usually a bad path or an adapter made for another model
+pub fn load_lora_adapters(
+    model: &LlamaModel,
+    adapters: &[LoraAdapterConfig],
+) -> Result<Vec<LoraAdapter>> {
+    adapters
+        .iter()
+        .map(|config| {
+            let adapter = model
+                .lora_adapter_init(&config.path)
+                .with_context(|| format!("failed to load LoRA adapter from {}", config.path))?;
+            Ok(LoraAdapter {
+                name: config.name(),
+                scale: config.scale.unwrap_or(1.0),
+                adapter: std::sync::Mutex::new(LoadedLoraAdapter(adapter)),
+            })
+        })
+        .collect()
+}
+
+/// Create a model manager for a list of model definitions
+/// Models are loaded when they're first used, see `ModelManager::get`
 /// # Arguments
 /// * `models` - The list of model definitions
+/// * `memory_budget` - The most memory (in bytes) loaded models may take, unlimited when `None`
 /// # Returns
 /// The model manager
 /// # Errors
-/// If any of the models fail to load
-pub fn load_models(models: Vec<types::ModelDefinition>) -> Result<ModelManager> {
-    let llama_backend = LlamaBackend::init().expect("failed to initialize llama_backend");
-    //let arc_llama_backend = Arc::new(llama_backend);
-    let mut loaded_models = HashMap::new();
-    for model in models {
-        let llama_model = load_model(model.path, model.config.clone(), &llama_backend)
-            .expect("failed to load model");
-        let model_state = types::ModelState {
-            model: llama_model,
-            config: model.config,
-            chat_template: model.chat_template,
-        };
-        let name = model.name.clone();
-        loaded_models.insert(name, model_state);
-    }
-    let model_manager = ModelManager {
-        models: loaded_models,
-        backend: llama_backend,
-    };
-    Ok(model_manager)
+/// If the llama backend fails to initialize
+pub fn load_models(
+    models: Vec<types::ModelDefinition>,
+    memory_budget: Option<u64>,
+) -> Result<ModelManager> {
+    let llama_backend = LlamaBackend::init().context("failed to initialize llama_backend")?;
+    Ok(ModelManager::new(llama_backend, models, memory_budget))
 }
 
 /// Generate a llama response
@@ -112,7 +141,8 @@ pub fn load_models(models: Vec<types::ModelDefinition>) -> Result<ModelManager>
 /// * `token_callback` - The token callback
 /// * `stops` - The list of stop words
 /// * `batch_size` - The batch size
-/// * `json_format` - Force json format
+/// * `grammar` - A GBNF grammar the output must follow
+/// * `sampling` - The sampling and penalty options
 /// # Returns
 /// * The llama result
 pub fn generate(
@@ -123,15 +153,20 @@ pub fn generate(
     token_callback: Option<TokenCallback>,
     stops: Option<&Vec<String>>,
     batch_size: u32,
-    json_format: bool,
+    grammar: Option<&GrammarConstraint>,
+    sampling: &SamplingParams,
 ) -> Result<LlamaResult> {
     let mut batch = LlamaBatch::new(batch_size as usize, 1); //TODO: make this buffer size real
     let last_index: i32 = (tokens_list.len() - 1) as i32;
-    let mut t = 0;
+    let mut history = TokenHistory::new();
     for (i, token) in (0_i32..).zip(tokens_list.into_iter()) {
         // llama_decode will output logits only for the last token of the prompt
         let is_last = i == last_index;
         batch.add(token, i, &[0], is_last)?;
+        let text = model
+            .token_to_str(token, Special::Plaintext)
+            .unwrap_or_default();
+        history.push(token, &text, sampling);
     }
     ctx.decode(&mut batch).expect("llama_decode() failed");
 
@@ -141,7 +176,15 @@ pub fn generate(
     let t_main_start = ggml_time_us();
     let mut generated_tokens = Vec::new();
     let mut generated_tokens_data = Vec::new();
-    let mut grammar = grammar::load_grammar();
+    let mut json_close = grammar.filter(|g| g.json).map(|_| JsonCloseTracker::new());
+    let mut grammar = grammar
+        .map(|g| grammar::compile_grammar(&g.grammar))
+        .transpose()?;
+    let mut rng = match sampling.seed {
+        Some(seed) => StdRng::seed_from_u64(u64::from(seed)),
+        None => StdRng::from_entropy(),
+    };
+    let mut mirostat_mu = 2.0 * sampling.mirostat_tau;
     loop {
         if n_cur >= n_len {
             break;
@@ -149,22 +192,22 @@ pub fn generate(
         let candidates = ctx.candidates_ith(batch.n_tokens() - 1);
         let mut candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
 
-        ctx.sample_temp(&mut candidates_p, 0.2); //TODO: make this a parameter with the model config object
-        if json_format {
-            ctx.sample_grammar(&mut candidates_p, &mut grammar);
+        sampling::apply_penalties(&mut candidates_p, &history, sampling);
+        sampling::apply_dry(&mut candidates_p, &history, sampling);
+        if let Some(ref mut grammar) = grammar {
+            ctx.sample_grammar(&mut candidates_p, grammar);
         }
-        ctx.sample_token_softmax(&mut candidates_p);
-        ctx.sample_top_k(&mut candidates_p, 5, 32);
-        ctx.sample_top_p(&mut candidates_p, 0.1, 32);
-        ctx.sample_typical(&mut candidates_p, 0.9, 32);
-        let new_token_id = candidates_p.data[0].id();
-        if json_format {
-            ctx.grammar_accept_token(&mut grammar, new_token_id);
+        let new_token_id =
+            sampling::sample_token(ctx, &mut candidates_p, sampling, &mut rng, &mut mirostat_mu);
+        if let Some(ref mut grammar) = grammar {
+            ctx.grammar_accept_token(grammar, new_token_id);
         }
         let token_str = model
             .token_to_str(new_token_id, Special::Plaintext)
             .expect("That UTF8 shit"); // We should make EOS a blank string
-        if new_token_id == model.token_eos() || find_stops(stops, &token_str) {
+
+        // EOS, EOT and any other token the model uses to end its turn
+        if model.is_eog_token(new_token_id) || find_stops(stops, &token_str) {
             break;
         }
         if let Some(ref token_callback) = token_callback {
@@ -172,8 +215,12 @@ pub fn generate(
         }
 
         print!("{}", token_str);
+        history.push(new_token_id, &token_str, sampling);
         generated_tokens.push(new_token_id);
         generated_tokens_data.push(token_str.clone()); //TODO: make that suck less
+        if json_close.as_mut().is_some_and(|t| t.feed(&token_str)) {
+            break; // The root JSON value is done, nothing more to sample
+        }
 
         batch.clear();
         batch.add(new_token_id, n_cur, &[0], true)?;
@@ -205,11 +252,14 @@ pub fn generate(
 /// * `max_tokens` - The maximum number of tokens
 /// * `stops` - The list of stops
 /// * `token_callback` - The token callback
-/// * `json_format` - Restrict output to json format
+/// * `grammar` - A GBNF grammar to restrict the output to
+/// * `sampling` - The sampling options, usually `model.config.sampling_params()` with request overrides
+/// * `lora` - The LoRA adapters to apply, `None` for the model's defaults
 /// # Returns
 /// * The llama result
 /// # Errors
 /// * If the model fails to tokenize the prompt
+/// * If a LoRA adapter isn't one the model has or can't be applied
 /// * If the model fails to generate the response
 pub fn pretty_generate(
     model: &ModelState,
@@ -218,11 +268,17 @@ pub fn pretty_generate(
     max_tokens: i32,
     stops: &Vec<String>,
     token_callback: Option<TokenCallback>,
-    json_format: Option<bool>,
+    grammar: Option<&GrammarConstraint>,
+    sampling: &SamplingParams,
+    lora: Option<&[LoraSelection]>,
 ) -> Result<LlamaResult> {
-    let mut rng = rand::thread_rng();
-    let random_number: u32 = rng.gen();
-    let context_size = model.config.num_ctx.unwrap_or(4096) as u32;
+    // The context and our own sampling draw from the same seed
+    let seed: u32 = sampling.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    let sampling = &SamplingParams {
+        seed: Some(seed),
+        ..sampling.clone()
+    };
+    let context_size = model.context_size() as u32;
     // tokenize the prompt
     let tokens_list = model
         .model
@@ -245,12 +301,33 @@ pub fn pretty_generate(
         //.with_n_ctx(i32_to_nonzero_u32(n_len)) // This could have issues and we should have a use max context values
         .with_n_ctx(NonZeroU32::new(context_size))
         .with_n_batch(n_len as u32)
-        .with_seed(random_number);
+        .with_seed(seed);
 
     let mut ctx = model
         .model
         .new_context(&backend, ctx_params)
         .with_context(|| "unable to create the llama_context")?;
+    let scales = model.lora_scales(lora).map_err(anyhow::Error::msg)?;
+    // The context reads the adapters all through generation, so they stay locked until it's
+    // done. Locked in the model's order and once each, so requests can't deadlock each other
+    let mut lora_guards = Vec::new();
+    for adapter in &model.lora_adapters {
+        let Some(scale) = scales
+            .iter()
+            .filter(|(selected, _)| std::ptr::eq(*selected, adapter))
+            .map(|(_, scale)| *scale)
+            .last()
+        else {
+            continue;
+        };
+        let mut guard = adapter
+            .adapter
+            .lock()
+            .map_err(|_| anyhow::anyhow!("LoRA adapter {} is poisoned", adapter.name))?;
+        ctx.lora_adapter_set(&mut guard.0, scale)
+            .with_context(|| format!("failed to apply LoRA adapter {}", adapter.name))?;
+        lora_guards.push(guard);
+    }
     let r = generate(
         &model.model,
         &mut ctx,
@@ -259,8 +336,10 @@ pub fn pretty_generate(
         token_callback,
         Some(stops),
         n_len as u32, // this logic will be different for Large context models
-        json_format.unwrap_or(false),
+        grammar,
+        sampling,
     )
     .expect("failed to generate");
+    drop(lora_guards);
     Ok(r)
 }
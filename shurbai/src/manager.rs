@@ -0,0 +1,798 @@
+//! Keeps track of the configured models, loading them on first use and unloading idle ones
+//! to stay within the memory budget
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use llama_cpp_2::llama_backend::LlamaBackend;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    gguf::{self, GgufMetadata, MetaValue},
+    load_lora_adapters, load_model,
+    types::{file_signature, Capability, ModelDefinition, ModelState, PromptTemplate},
+};
+
+/// How long a model stays loaded after its last request when `keep_alive` isn't set
+const DEFAULT_KEEP_ALIVE_SECS: i64 = 300;
+
+struct ModelEntry {
+    definition: ModelDefinition,
+    metadata: GgufMetadata,
+    capabilities: Vec<Capability>,
+    estimated_bytes: u64,
+    loaded: Option<Arc<ModelState>>,
+    /// Set while a request loads the model outside the lock, to the id of that load
+    loading: Option<u64>,
+    /// Added or changed through the admin API, config reloads leave it alone
+    runtime: bool,
+    last_used: Instant,
+}
+
+impl ModelEntry {
+    /// Read the GGUF header up front, it's cheap and tells us what the model can do
+    fn new(definition: ModelDefinition) -> Self {
+        let metadata = gguf::read_metadata(&definition.path).unwrap_or_else(|e| {
+            println!(
+                "could not read GGUF metadata for {}: {}",
+                definition.name, e
+            );
+            GgufMetadata::default()
+        });
+        let mut capabilities = ModelState::detect_capabilities(&definition.config, &metadata);
+        if definition.config.capabilities.is_none()
+            && PromptTemplate::resolve(&definition, &metadata).is_none()
+        {
+            // Nothing to build a chat prompt with
+            capabilities.retain(|c| *c != Capability::Chat);
+        }
+        let estimated_bytes = estimate_memory(&definition, &metadata);
+        ModelEntry {
+            definition,
+            metadata,
+            capabilities,
+            estimated_bytes,
+            loaded: None,
+            loading: None,
+            runtime: false,
+            last_used: Instant::now(),
+        }
+    }
+
+    fn status(&self) -> ModelStatus {
+        ModelStatus {
+            name: self.definition.name.clone(),
+            aliases: self.definition.aliases.clone(),
+            capabilities: self.capabilities.clone(),
+            loaded: self.loaded.is_some(),
+            estimated_bytes: self.estimated_bytes,
+        }
+    }
+
+    /// Unload the model, handing back what's needed to track it while requests drain
+    fn take_loaded(&mut self) -> Option<(Arc<ModelState>, u64)> {
+        self.loaded.take().map(|m| (m, self.estimated_bytes))
+    }
+
+    /// Loaded and not held by any request
+    fn is_idle(&self) -> bool {
+        self.loaded
+            .as_ref()
+            .is_some_and(|m| Arc::strong_count(m) == 1)
+    }
+
+    /// A negative keep alive pins the model once it's loaded
+    fn keep_alive(&self) -> Option<Duration> {
+        let secs = self
+            .definition
+            .config
+            .keep_alive
+            .unwrap_or(DEFAULT_KEEP_ALIVE_SECS);
+        (secs >= 0).then(|| Duration::from_secs(secs as u64))
+    }
+}
+
+/// A snapshot of one configured model
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelStatus {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub capabilities: Vec<Capability>,
+    pub loaded: bool,
+    pub estimated_bytes: u64,
+}
+
+/// Server wide rules for picking a model when a request doesn't name a configured one
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Routing {
+    pub default_model: Option<String>, // Used when a request leaves the model out
+    pub fallback_models: Vec<String>,  // Tried in order when a model isn't configured or won't load
+}
+
+/// What changed when the model definitions were swapped out
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+pub struct ModelManager {
+    pub backend: LlamaBackend,
+    entries: Mutex<HashMap<String, ModelEntry>>,
+    /// Replaced or removed models still serving requests, with their estimated size
+    retired: Mutex<Vec<(Arc<ModelState>, u64)>>,
+    memory_budget: Option<u64>,
+    routing: Mutex<Routing>,
+    /// Signalled whenever a load finishes, requests for a model that's loading wait on it
+    load_finished: Condvar,
+    next_load: AtomicU64,
+}
+
+/// The name a model is registered under, looking through the aliases when it isn't one
+/// If two models claim the same alias the first by name wins
+fn canonical(entries: &HashMap<String, ModelEntry>, name: &str) -> Option<String> {
+    if entries.contains_key(name) {
+        return Some(name.to_string());
+    }
+    entries
+        .values()
+        .filter(|e| e.definition.aliases.iter().any(|a| a == name))
+        .map(|e| e.definition.name.clone())
+        .min()
+}
+
+/// The routing decision behind `ModelManager::route`
+fn pick_route(
+    entries: &HashMap<String, ModelEntry>,
+    routing: &Routing,
+    requested: Option<&str>,
+    accepts: &[Capability],
+) -> Option<String> {
+    let requested = requested.or(routing.default_model.as_deref())?;
+    let configured = canonical(entries, requested);
+    if let Some(name) = &configured {
+        if supports(&entries[name], accepts) {
+            return configured;
+        }
+    }
+    // The model's own fallbacks come first, the same order `get_or_fallback` tries them in
+    let own = configured
+        .as_ref()
+        .map(|name| entries[name].definition.fallbacks.as_slice())
+        .unwrap_or_default();
+    own.iter()
+        .chain(&routing.fallback_models)
+        .filter_map(|fallback| canonical(entries, fallback))
+        .find(|name| supports(&entries[name], accepts))
+        // Nothing fits, the request goes to the model asked for so the route can say what it
+        // can't do
+        .or(configured)
+}
+
+/// Compared through JSON so the config types don't all need `PartialEq`
+fn same_definition(a: &ModelDefinition, b: &ModelDefinition) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+fn supports(entry: &ModelEntry, accepts: &[Capability]) -> bool {
+    accepts.iter().any(|c| entry.capabilities.contains(c))
+}
+
+/// Rough memory a model needs: its weights and adapters plus an f16 KV cache for `num_ctx` tokens
+/// That's one context's worth, every request makes its own context so each one running at the
+/// same time needs another KV cache on top of this
+fn estimate_memory(definition: &ModelDefinition, metadata: &GgufMetadata) -> u64 {
+    let file_size = |path: &str| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let weights = file_size(&definition.path)
+        + definition
+            .lora_adapters
+            .iter()
+            .map(|a| file_size(&a.path))
+            .sum::<u64>();
+    let arch_u64 = |key: &str| metadata.arch_value(key).and_then(MetaValue::as_u64);
+    let (Some(n_layer), Some(n_embd)) = (arch_u64("block_count"), arch_u64("embedding_length"))
+    else {
+        return weights;
+    };
+    let n_head = arch_u64("attention.head_count").unwrap_or(1).max(1);
+    let n_head_kv = arch_u64("attention.head_count_kv").unwrap_or(n_head);
+    let n_ctx = definition.config.context_size() as u64;
+    // K and V, two bytes per value
+    let kv_cache = 2 * n_ctx * n_layer * (n_embd * n_head_kv / n_head) * 2;
+    weights + kv_cache
+}
+
+/// Clears a load's mark and wakes the requests waiting on it however the load ends, so a
+/// panic while loading leaves the model free to load again rather than stuck loading
+struct LoadingGuard<'a> {
+    manager: &'a ModelManager,
+    load_id: u64,
+}
+
+impl Drop for LoadingGuard<'_> {
+    fn drop(&mut self) {
+        let mut entries = self
+            .manager
+            .entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = entries
+            .values_mut()
+            .find(|e| e.loading == Some(self.load_id))
+        {
+            entry.loading = None;
+        }
+        drop(entries);
+        self.manager.load_finished.notify_all();
+    }
+}
+
+impl ModelManager {
+    /// Register the models without loading any of them
+    /// Only the GGUF headers are read, to work out capabilities and memory estimates
+    pub fn new(
+        backend: LlamaBackend,
+        definitions: Vec<ModelDefinition>,
+        memory_budget: Option<u64>,
+    ) -> Self {
+        let entries = definitions
+            .into_iter()
+            .map(|definition| (definition.name.clone(), ModelEntry::new(definition)))
+            .collect();
+        ModelManager {
+            backend,
+            entries: Mutex::new(entries),
+            retired: Mutex::new(Vec::new()),
+            memory_budget,
+            routing: Mutex::new(Routing::default()),
+            load_finished: Condvar::new(),
+            next_load: AtomicU64::new(0),
+        }
+    }
+
+    /// Replace the default model and fallbacks, done along with every reload
+    pub fn set_routing(&self, routing: Routing) {
+        *self.routing.lock().unwrap() = routing;
+    }
+
+    /// The name a model or alias is registered under, `None` when it isn't configured
+    pub fn resolve(&self, name: &str) -> Option<String> {
+        canonical(&self.entries.lock().unwrap(), name)
+    }
+
+    /// Pick the model that serves a request for `requested`, a model name or alias
+    /// Leaving it out picks the default model. A name that isn't configured, or a model that
+    /// can't do any of `accepts`, picks the first of its own fallbacks and then the server's
+    /// that can
+    /// Returns `None` when the name isn't configured and no fallback fits
+    pub fn route(&self, requested: Option<&str>, accepts: &[Capability]) -> Option<String> {
+        let routing = self.routing.lock().unwrap().clone();
+        pick_route(&self.entries.lock().unwrap(), &routing, requested, accepts)
+    }
+
+    /// Swap in a new set of model definitions
+    /// Unchanged models stay loaded, changed ones load with their new definition on next use
+    /// Models added or changed through the admin API are kept as they are, even when the new
+    /// definitions leave them out or define them differently
+    /// Requests already holding a replaced or removed model finish on it, the old copy is
+    /// dropped once they're done and counts against the memory budget until then
+    pub fn reload(&self, definitions: Vec<ModelDefinition>) -> ReloadSummary {
+        // Reading the GGUF headers of new and changed models touches the disk, do it before
+        // taking the lock so requests aren't held up
+        let (current, runtime): (HashMap<String, ModelDefinition>, Vec<String>) = {
+            let entries = self.entries.lock().unwrap();
+            let current = entries
+                .iter()
+                .map(|(name, entry)| (name.clone(), entry.definition.clone()))
+                .collect();
+            let runtime = entries
+                .iter()
+                .filter(|(_, entry)| entry.runtime)
+                .map(|(name, _)| name.clone())
+                .collect();
+            (current, runtime)
+        };
+        let definitions: Vec<ModelDefinition> = definitions
+            .into_iter()
+            .filter(|d| !runtime.contains(&d.name))
+            .collect();
+        let mut fresh: HashMap<String, ModelEntry> = definitions
+            .iter()
+            .filter(|d| !current.get(&d.name).is_some_and(|c| same_definition(c, d)))
+            .map(|d| (d.name.clone(), ModelEntry::new(d.clone())))
+            .collect();
+
+        let mut entries = self.entries.lock().unwrap();
+        let mut retired = self.retired.lock().unwrap();
+        let mut summary = ReloadSummary::default();
+        let (runtime_entries, mut previous): (HashMap<_, _>, HashMap<_, _>) =
+            std::mem::take(&mut *entries)
+                .into_iter()
+                .partition(|(_, entry)| entry.runtime);
+        *entries = runtime_entries;
+        for definition in definitions {
+            let name = definition.name.clone();
+            if entries.get(&name).is_some_and(|e| e.runtime) {
+                continue;
+            }
+            // Only missing when the model changed again between the two locks
+            let new_entry = |fresh: &mut HashMap<String, ModelEntry>| {
+                fresh
+                    .remove(&name)
+                    .filter(|e| same_definition(&e.definition, &definition))
+                    .unwrap_or_else(|| ModelEntry::new(definition.clone()))
+            };
+            match previous.remove(&name) {
+                Some(entry) if same_definition(&entry.definition, &definition) => {
+                    entries.insert(name, entry);
+                }
+                Some(mut entry) => {
+                    retired.extend(entry.take_loaded());
+                    entries.insert(name.clone(), new_entry(&mut fresh));
+                    summary.changed.push(name);
+                }
+                None => {
+                    entries.insert(name.clone(), new_entry(&mut fresh));
+                    summary.added.push(name);
+                }
+            }
+        }
+        for (name, mut entry) in previous {
+            retired.extend(entry.take_loaded());
+            summary.removed.push(name);
+        }
+        // Anything nobody is using can go straight away
+        retired.retain(|(m, _)| Arc::strong_count(m) > 1);
+        summary.added.sort();
+        summary.removed.sort();
+        summary.changed.sort();
+        summary
+    }
+
+    /// Register a model through the admin API unless a model or alias already has its name
+    /// Returns false when the name is taken
+    pub fn add(&self, definition: ModelDefinition) -> bool {
+        let name = definition.name.clone();
+        let mut entry = ModelEntry::new(definition);
+        entry.runtime = true;
+        let mut entries = self.entries.lock().unwrap();
+        if canonical(&entries, &name).is_some() {
+            return false;
+        }
+        entries.insert(name, entry);
+        true
+    }
+
+    /// Change a model's definition through the admin API
+    /// Like a reload, requests holding the old model finish on it. A model that was loaded is
+    /// loaded again with the new definition straight away, and when that fails the old
+    /// definition goes back in, still loaded
+    /// # Errors
+    /// If the model isn't configured or the new definition fails to load
+    pub fn update(&self, definition: ModelDefinition) -> Result<()> {
+        let name = definition.name.clone();
+        let mut entry = ModelEntry::new(definition.clone());
+        entry.runtime = true;
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&name) {
+            bail!("model {} is not configured", name);
+        }
+        let previous = entries.insert(name.clone(), entry).expect("checked above");
+        // The old model keeps counting against the budget while the new one loads
+        let Some(old_model) = previous.loaded.clone() else {
+            return Ok(());
+        };
+        self.retired
+            .lock()
+            .unwrap()
+            .push((old_model.clone(), previous.estimated_bytes));
+        drop(entries);
+
+        let error = match self.get(&name) {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        let untouched = entries.get(&name).is_some_and(|e| {
+            e.loaded.is_none() && e.loading.is_none() && same_definition(&e.definition, &definition)
+        });
+        if untouched {
+            self.retired
+                .lock()
+                .unwrap()
+                .retain(|(m, _)| !Arc::ptr_eq(m, &old_model));
+            entries.insert(name, previous);
+        }
+        Err(error)
+    }
+
+    /// Forget a model, returns false when it wasn't configured
+    pub fn remove(&self, name: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let previous = canonical(&entries, name).and_then(|name| entries.remove(&name));
+        drop(entries);
+        match previous {
+            Some(mut previous) => {
+                self.retire(previous.take_loaded());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Free a model's memory but keep it configured, the next request loads it again
+    /// Returns false when it wasn't configured
+    pub fn unload(&self, name: &str) -> bool {
+        let loaded = match self.entry(name, |entry| entry.take_loaded()) {
+            Some(loaded) => loaded,
+            None => return false,
+        };
+        self.retire(loaded);
+        true
+    }
+
+    fn retire(&self, loaded: Option<(Arc<ModelState>, u64)>) {
+        let mut retired = self.retired.lock().unwrap();
+        retired.extend(loaded);
+        retired.retain(|(m, _)| Arc::strong_count(m) > 1);
+    }
+
+    /// Look at a model's entry by name or alias
+    fn entry<T>(&self, name: &str, f: impl FnOnce(&mut ModelEntry) -> T) -> Option<T> {
+        let mut entries = self.entries.lock().unwrap();
+        let name = canonical(&entries, name)?;
+        entries.get_mut(&name).map(f)
+    }
+
+    /// The definition a model is currently registered with
+    pub fn definition(&self, name: &str) -> Option<ModelDefinition> {
+        self.entry(name, |e| e.definition.clone())
+    }
+
+    /// The GGUF header of a model, read when it was registered
+    pub fn metadata(&self, name: &str) -> Option<GgufMetadata> {
+        self.entry(name, |e| e.metadata.clone())
+    }
+
+    pub fn has_model(&self, name: &str) -> bool {
+        self.resolve(name).is_some()
+    }
+
+    /// What a model can do, known without loading it
+    pub fn capabilities(&self, name: &str) -> Option<Vec<Capability>> {
+        self.entry(name, |e| e.capabilities.clone())
+    }
+
+    /// Every configured model, sorted by name
+    pub fn status(&self) -> Vec<ModelStatus> {
+        let entries = self.entries.lock().unwrap();
+        let mut status: Vec<ModelStatus> = entries.values().map(ModelEntry::status).collect();
+        status.sort_by(|a, b| a.name.cmp(&b.name));
+        status
+    }
+
+    /// One model's status, `None` when it isn't configured
+    pub fn model_status(&self, name: &str) -> Option<ModelStatus> {
+        self.entry(name, |e| e.status())
+    }
+
+    /// Get a model, loading it first if needed
+    /// The load runs without holding the lock, other requests for the same model wait for it
+    /// and everything else carries on
+    /// The returned handle keeps the model loaded until it's dropped
+    /// # Errors
+    /// If the model isn't configured, fails to load or doesn't fit in the memory budget
+    pub fn get(&self, name: &str) -> Result<Arc<ModelState>> {
+        let mut entries = self.entries.lock().unwrap();
+        let name = loop {
+            let name = canonical(&entries, name)
+                .ok_or_else(|| anyhow!("model {} is not configured", name))?;
+            let entry = entries.get_mut(&name).expect("canonical names are keys");
+            entry.last_used = Instant::now();
+            if let Some(model) = &entry.loaded {
+                return Ok(model.clone());
+            }
+            if entry.loading.is_none() {
+                break name;
+            }
+            entries = self.load_finished.wait(entries).unwrap();
+        };
+        let needed = entries[&name].estimated_bytes;
+        let draining = self.draining_bytes();
+        Self::make_room(&mut entries, self.memory_budget, &name, needed + draining)?;
+
+        let load_id = self.next_load.fetch_add(1, Ordering::Relaxed);
+        let entry = entries.get_mut(&name).expect("entry was found above");
+        entry.loading = Some(load_id);
+        let definition = entry.definition.clone();
+        let metadata = entry.metadata.clone();
+        let capabilities = entry.capabilities.clone();
+        drop(entries);
+
+        let guard = LoadingGuard {
+            manager: self,
+            load_id,
+        };
+        println!("loading model {}", name);
+        let loaded = self.load(definition, metadata, capabilities);
+
+        let mut entries = self.entries.lock().unwrap();
+        match entries.values_mut().find(|e| e.loading == Some(load_id)) {
+            Some(entry) => {
+                entry.loading = None;
+                entry.loaded = loaded.as_ref().ok().cloned();
+            }
+            // Replaced or removed while it loaded, this request still gets the copy it asked for
+            None => {
+                if let Ok(model) = &loaded {
+                    self.retire(Some((model.clone(), needed)));
+                }
+            }
+        }
+        drop(entries);
+        drop(guard);
+        loaded
+    }
+
+    fn load(
+        &self,
+        definition: ModelDefinition,
+        metadata: GgufMetadata,
+        capabilities: Vec<Capability>,
+    ) -> Result<Arc<ModelState>> {
+        let model = load_model(
+            definition.path.clone(),
+            definition.config.clone(),
+            &self.backend,
+        )?;
+        let lora_adapters = load_lora_adapters(&model, &definition.lora_adapters)?;
+        Ok(Arc::new(ModelState {
+            lora_adapters,
+            model,
+            chat_template: PromptTemplate::resolve(&definition, &metadata),
+            file_signature: file_signature(&definition.path),
+            config: definition.config,
+            metadata,
+            capabilities,
+            embedding_prefixes: definition.embedding_prefixes,
+        }))
+    }
+
+    /// Get a model like `get`, but when it can't be loaded try its own fallbacks and then the
+    /// server's, skipping any that can't do one of `accepts`
+    /// Returns the name of the model that was picked along with it
+    /// # Errors
+    /// The requested model's error when none of the fallbacks load either
+    pub fn get_or_fallback(
+        &self,
+        name: &str,
+        accepts: &[Capability],
+    ) -> Result<(String, Arc<ModelState>)> {
+        let error = match self.get(name) {
+            Ok(model) => return Ok((self.resolve(name).unwrap_or(name.to_string()), model)),
+            Err(e) => e,
+        };
+        let mut candidates = self
+            .definition(name)
+            .map(|d| d.fallbacks)
+            .unwrap_or_default();
+        candidates.extend(self.routing.lock().unwrap().fallback_models.clone());
+        let mut tried = vec![self.resolve(name).unwrap_or(name.to_string())];
+        for candidate in candidates {
+            let Some(candidate) = self.resolve(&candidate) else {
+                continue;
+            };
+            if tried.contains(&candidate)
+                || self.entry(&candidate, |e| supports(e, accepts)) != Some(true)
+            {
+                continue;
+            }
+            tried.push(candidate.clone());
+            if let Ok(model) = self.get(&candidate) {
+                println!(
+                    "{} is unavailable ({}), using {} instead",
+                    name, error, candidate
+                );
+                return Ok((candidate, model));
+            }
+        }
+        Err(error)
+    }
+
+    /// Unload the least recently used idle models until `needed` more bytes fit in the budget
+    fn make_room(
+        entries: &mut HashMap<String, ModelEntry>,
+        memory_budget: Option<u64>,
+        loading: &str,
+        needed: u64,
+    ) -> Result<()> {
+        let Some(budget) = memory_budget else {
+            return Ok(());
+        };
+        loop {
+            let in_use: u64 = entries
+                .values()
+                .filter(|e| e.loaded.is_some() || e.loading.is_some())
+                .map(|e| e.estimated_bytes)
+                .sum();
+            if in_use + needed <= budget {
+                return Ok(());
+            }
+            let victim = entries
+                .iter()
+                .filter(|(name, e)| *name != loading && e.is_idle() && e.keep_alive().is_some())
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(name, _)| name.clone());
+            let Some(victim) = victim else {
+                bail!(
+                    "not enough memory budget to load {} ({} MB needed, {} MB held by busy or pinned models)",
+                    loading,
+                    needed / 1_000_000,
+                    in_use / 1_000_000
+                );
+            };
+            println!("unloading model {} to make room for {}", victim, loading);
+            entries.get_mut(&victim).expect("victim exists").loaded = None;
+        }
+    }
+
+    /// Memory still held by retired models, dropping the ones that have finished
+    fn draining_bytes(&self) -> u64 {
+        let mut retired = self.retired.lock().unwrap();
+        retired.retain(|(m, _)| Arc::strong_count(m) > 1);
+        retired.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    /// Unload models that have been idle for longer than their keep alive
+    /// Returns the names of the models that were unloaded
+    pub fn unload_idle(&self) -> Vec<String> {
+        self.draining_bytes();
+        let mut entries = self.entries.lock().unwrap();
+        let mut unloaded = Vec::new();
+        for (name, entry) in entries.iter_mut() {
+            if entry.loaded.is_none() {
+                continue;
+            }
+            if !entry.is_idle() {
+                // Still generating, the keep alive counts from when it's done
+                entry.last_used = Instant::now();
+                continue;
+            }
+            if let Some(keep_alive) = entry.keep_alive() {
+                if entry.last_used.elapsed() >= keep_alive {
+                    entry.loaded = None;
+                    unloaded.push(name.clone());
+                }
+            }
+        }
+        unloaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(models: &[(&str, &[&str], &str)]) -> HashMap<String, ModelEntry> {
+        models
+            .iter()
+            .map(|(name, aliases, capability)| {
+                let definition: ModelDefinition = serde_json::from_value(serde_json::json!({
+                    "name": name,
+                    "path": format!("/nonexistent/{}.gguf", name),
+                    "aliases": aliases,
+                    "config": { "capabilities": [capability] }
+                }))
+                .unwrap();
+                (name.to_string(), ModelEntry::new(definition))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn canonical_resolves_names_and_aliases() {
+        let entries = entries(&[
+            ("llama", &["default", "chat"], "chat"),
+            ("qwen", &[], "chat"),
+        ]);
+        assert_eq!(canonical(&entries, "llama").as_deref(), Some("llama"));
+        assert_eq!(canonical(&entries, "chat").as_deref(), Some("llama"));
+        assert_eq!(canonical(&entries, "mistral"), None);
+    }
+
+    #[test]
+    fn shared_aliases_go_to_the_first_name() {
+        let entries = entries(&[("b", &["shared"], "chat"), ("a", &["shared"], "chat")]);
+        assert_eq!(canonical(&entries, "shared").as_deref(), Some("a"));
+        // A model's own name beats another model's alias
+        let entries = self::entries(&[("a", &["b"], "chat"), ("b", &[], "chat")]);
+        assert_eq!(canonical(&entries, "b").as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn route_uses_the_default_model_when_none_is_given() {
+        let entries = entries(&[("llama", &["main"], "chat")]);
+        let routing = Routing {
+            default_model: Some("main".to_string()),
+            fallback_models: vec![],
+        };
+        let chat = &[Capability::Chat];
+        assert_eq!(
+            pick_route(&entries, &routing, None, chat).as_deref(),
+            Some("llama")
+        );
+        assert_eq!(pick_route(&entries, &Routing::default(), None, chat), None);
+    }
+
+    #[test]
+    fn route_falls_back_to_a_model_that_can_serve_the_request() {
+        let entries = entries(&[("embedder", &[], "embedding"), ("llama", &[], "chat")]);
+        let routing = Routing {
+            default_model: None,
+            fallback_models: vec!["missing".into(), "embedder".into(), "llama".into()],
+        };
+        assert_eq!(
+            pick_route(&entries, &routing, Some("gpt-4"), &[Capability::Chat]).as_deref(),
+            Some("llama")
+        );
+        assert_eq!(
+            pick_route(&entries, &routing, Some("gpt-4"), &[Capability::Embedding]).as_deref(),
+            Some("embedder")
+        );
+        // A configured model that can't serve the request is swapped for a fallback that can
+        assert_eq!(
+            pick_route(&entries, &routing, Some("embedder"), &[Capability::Chat]).as_deref(),
+            Some("llama")
+        );
+        assert_eq!(
+            pick_route(
+                &entries,
+                &routing,
+                Some("embedder"),
+                &[Capability::Embedding]
+            )
+            .as_deref(),
+            Some("embedder")
+        );
+    }
+
+    #[test]
+    fn route_keeps_the_requested_model_when_no_fallback_fits() {
+        let entries = entries(&[("embedder", &[], "embedding"), ("llama", &[], "chat")]);
+        let routing = Routing {
+            default_model: None,
+            fallback_models: vec!["embedder".into()],
+        };
+        // Left to the route's capability check, which explains what the model can do
+        assert_eq!(
+            pick_route(&entries, &routing, Some("embedder"), &[Capability::Chat]).as_deref(),
+            Some("embedder")
+        );
+        assert_eq!(
+            pick_route(&entries, &routing, Some("gpt-4"), &[Capability::Chat]),
+            None
+        );
+    }
+
+    #[test]
+    fn route_prefers_the_models_own_fallbacks() {
+        let mut entries = entries(&[
+            ("embedder", &[], "embedding"),
+            ("llama", &[], "chat"),
+            ("qwen", &[], "chat"),
+        ]);
+        entries.get_mut("embedder").unwrap().definition.fallbacks = vec!["qwen".into()];
+        let routing = Routing {
+            default_model: None,
+            fallback_models: vec!["llama".into()],
+        };
+        assert_eq!(
+            pick_route(&entries, &routing, Some("embedder"), &[Capability::Chat]).as_deref(),
+            Some("qwen")
+        );
+    }
+}
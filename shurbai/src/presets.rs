@@ -0,0 +1,253 @@
+//! Ready made per role templates for the common chat formats, so a model definition can
+//! say `"chat_template": { "preset": "chatml" }` instead of spelling every template out
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::ChatTemplate;
+
+/// The chat formats there is a built in `ChatTemplate` for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatTemplatePreset {
+    /// `<|im_start|>role ... <|im_end|>`, used by Qwen, Hermes, Dolphin and friends
+    Chatml,
+    /// `[INST] ... [/INST]` with a `<<SYS>>` block
+    Llama2,
+    /// `<|start_header_id|>role<|end_header_id|> ... <|eot_id|>`
+    Llama3,
+    /// `[INST] ... [/INST]` without a system role
+    Mistral,
+    /// `<start_of_turn>role ... <end_of_turn>`, the system prompt goes in a user turn
+    Gemma,
+    /// `<|role|> ... <|end|>`
+    Phi3,
+    /// `### Instruction:` / `### Response:`
+    Alpaca,
+    /// `USER:` / `ASSISTANT:`
+    Vicuna,
+}
+
+/// What the model is told about tools, wrapped in each preset's system turn
+const TOOL_INSTRUCTIONS: &str = "You are a function calling AI model. You are provided with function signatures within <tools></tools> XML tags. You may call one or more functions to assist with the user query. You can also engage in general conversation. Don't make assumptions about what values to plug into functions. Here are all the available tools, do not use any not in this list: <tools> {tools} </tools> Use the following pydantic model json schema for each tool call you will make {template} For each function call return a json object with function name and arguments within <tool_call></tool_call> XML tags as follows:\n<tool_call>\n{template}\n</tool_call>\nSome functions will give back data, that data will be given by the agent 'tool'";
+
+fn template(
+    system: &str,
+    user: &str,
+    assistant: &str,
+    tool_response: &str,
+    assistant_prompt: &str,
+    stops: &[&str],
+) -> ChatTemplate {
+    ChatTemplate {
+        user_template: user.to_string(),
+        system_template: system.to_string(),
+        assistant_template: assistant.to_string(),
+        tool_response_template: Some(tool_response.to_string()),
+        tool_template: system.replace("{content}", TOOL_INSTRUCTIONS),
+        tool_prompt_template: None,
+        assistant_prompt_template: assistant_prompt.to_string(),
+        stops: stops.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+impl ChatTemplatePreset {
+    /// The full template for this format, with the stops that end an assistant turn
+    pub fn template(self) -> ChatTemplate {
+        match self {
+            ChatTemplatePreset::Chatml => template(
+                "<|im_start|>system\n{content}<|im_end|>\n",
+                "<|im_start|>user\n{content}<|im_end|>\n",
+                "<|im_start|>assistant\n{content}<|im_end|>\n",
+                "<|im_start|>tool\n<tool_response>\n{content}\n</tool_response><|im_end|>\n",
+                "<|im_start|>assistant\n",
+                &["<|im_end|>", "<|im_start|>"],
+            ),
+            ChatTemplatePreset::Llama2 => template(
+                "[INST] <<SYS>>\n{content}\n<</SYS>> [/INST]\n",
+                "[INST] {content} [/INST]",
+                " {content} </s>",
+                "[INST] <tool_response>\n{content}\n</tool_response> [/INST]",
+                "",
+                &["[INST]", "</s>", "<<SYS>>", "<</SYS>>"],
+            ),
+            ChatTemplatePreset::Llama3 => template(
+                "<|start_header_id|>system<|end_header_id|>\n\n{content}<|eot_id|>",
+                "<|start_header_id|>user<|end_header_id|>\n\n{content}<|eot_id|>",
+                "<|start_header_id|>assistant<|end_header_id|>\n\n{content}<|eot_id|>",
+                "<|start_header_id|>ipython<|end_header_id|>\n\n{content}<|eot_id|>",
+                "<|start_header_id|>assistant<|end_header_id|>\n\n",
+                &["<|eot_id|>", "<|end_of_text|>", "<|start_header_id|>"],
+            ),
+            ChatTemplatePreset::Mistral => template(
+                "[INST] {content} [/INST]",
+                "[INST] {content} [/INST]",
+                "{content}</s>",
+                "[TOOL_RESULTS] {content}[/TOOL_RESULTS]",
+                "",
+                &["</s>", "[INST]"],
+            ),
+            ChatTemplatePreset::Gemma => template(
+                "<start_of_turn>user\n{content}<end_of_turn>\n",
+                "<start_of_turn>user\n{content}<end_of_turn>\n",
+                "<start_of_turn>model\n{content}<end_of_turn>\n",
+                "<start_of_turn>user\n<tool_response>\n{content}\n</tool_response><end_of_turn>\n",
+                "<start_of_turn>model\n",
+                &["<end_of_turn>", "<start_of_turn>"],
+            ),
+            ChatTemplatePreset::Phi3 => template(
+                "<|system|>\n{content}<|end|>\n",
+                "<|user|>\n{content}<|end|>\n",
+                "<|assistant|>\n{content}<|end|>\n",
+                "<|user|>\n<tool_response>\n{content}\n</tool_response><|end|>\n",
+                "<|assistant|>\n",
+                &["<|end|>", "<|endoftext|>", "<|user|>"],
+            ),
+            ChatTemplatePreset::Alpaca => template(
+                "{content}\n\n",
+                "### Instruction:\n{content}\n\n",
+                "### Response:\n{content}\n\n",
+                "### Input:\n{content}\n\n",
+                "### Response:\n",
+                &["### Instruction:", "### Input:", "### Response:"],
+            ),
+            ChatTemplatePreset::Vicuna => template(
+                "{content}\n\n",
+                "USER: {content}\n",
+                "ASSISTANT: {content}</s>\n",
+                "USER: <tool_response>\n{content}\n</tool_response>\n",
+                "ASSISTANT:",
+                &["USER:", "</s>"],
+            ),
+        }
+    }
+}
+
+/// Fields to change on top of a preset, anything left out keeps the preset's value
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatTemplateOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assistant_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_response_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_prompt_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assistant_prompt_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stops: Option<Vec<String>>, // replaces the preset's stops rather than adding to them
+}
+
+/// A preset and the fields a model changes on it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PresetTemplate {
+    pub preset: ChatTemplatePreset,
+    #[serde(flatten)]
+    pub overrides: ChatTemplateOverrides,
+}
+
+impl PresetTemplate {
+    pub fn template(&self) -> ChatTemplate {
+        let mut template = self.preset.template();
+        let overrides = self.overrides.clone();
+        let set = |field: &mut String, value: Option<String>| {
+            if let Some(value) = value {
+                *field = value;
+            }
+        };
+        set(&mut template.user_template, overrides.user_template);
+        set(&mut template.system_template, overrides.system_template);
+        set(
+            &mut template.assistant_template,
+            overrides.assistant_template,
+        );
+        set(&mut template.tool_template, overrides.tool_template);
+        set(
+            &mut template.assistant_prompt_template,
+            overrides.assistant_prompt_template,
+        );
+        if overrides.tool_response_template.is_some() {
+            template.tool_response_template = overrides.tool_response_template;
+        }
+        if overrides.tool_prompt_template.is_some() {
+            template.tool_prompt_template = overrides.tool_prompt_template;
+        }
+        if let Some(stops) = overrides.stops {
+            template.stops = stops;
+        }
+        template
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [ChatTemplatePreset; 8] = [
+        ChatTemplatePreset::Chatml,
+        ChatTemplatePreset::Llama2,
+        ChatTemplatePreset::Llama3,
+        ChatTemplatePreset::Mistral,
+        ChatTemplatePreset::Gemma,
+        ChatTemplatePreset::Phi3,
+        ChatTemplatePreset::Alpaca,
+        ChatTemplatePreset::Vicuna,
+    ];
+
+    #[test]
+    fn every_preset_renders_content_and_stops() {
+        for preset in ALL {
+            let template = preset.template();
+            for part in [
+                &template.user_template,
+                &template.system_template,
+                &template.assistant_template,
+                template.tool_response_template.as_ref().unwrap(),
+            ] {
+                assert!(part.contains("{content}"), "{:?}: {}", preset, part);
+            }
+            assert!(template.tool_template.contains("{tools}"), "{:?}", preset);
+            assert!(
+                !template.tool_template.contains("{content}"),
+                "{:?}",
+                preset
+            );
+            assert!(!template.stops.is_empty(), "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn overrides_replace_only_the_fields_given() {
+        let preset: PresetTemplate = serde_json::from_value(serde_json::json!({
+            "preset": "chatml",
+            "system_template": "SYS {content}\n",
+            "stops": ["<|endoftext|>"]
+        }))
+        .unwrap();
+        let template = preset.template();
+        let chatml = ChatTemplatePreset::Chatml.template();
+        assert_eq!(template.system_template, "SYS {content}\n");
+        assert_eq!(template.user_template, chatml.user_template);
+        assert_eq!(
+            template.tool_response_template,
+            chatml.tool_response_template
+        );
+        // Stops replace the preset's rather than adding to them
+        assert_eq!(template.stops, vec!["<|endoftext|>".to_string()]);
+    }
+
+    #[test]
+    fn presets_without_overrides_are_unchanged() {
+        let preset: PresetTemplate =
+            serde_json::from_value(serde_json::json!({ "preset": "llama3" })).unwrap();
+        let template = preset.template();
+        let llama3 = ChatTemplatePreset::Llama3.template();
+        assert_eq!(template.assistant_template, llama3.assistant_template);
+        assert_eq!(template.stops, llama3.stops);
+    }
+}
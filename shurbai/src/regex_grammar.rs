@@ -0,0 +1,515 @@
+//! Compile a regular expression into a GBNF grammar
+//! Supports literals, `.`, character classes, the `\d \w \s` shorthands (and their negations),
+//! `\xHH` and `\uHHHH` escapes, groups, alternation and the `* + ? {n} {n,} {,m} {n,m}`
+//! quantifiers. `^` and `$` are only accepted at the start and end of a top level branch.
+//! Anything else GBNF can't express is an error rather than a looser grammar.
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::json_schema::{gbnf_literal, repeat};
+
+/// Convert a regex into a GBNF grammar rooted at `root`
+/// The whole output has to match, so anchors make no difference here
+/// # Errors
+/// If the pattern is malformed or uses a feature GBNF can't express (backreferences, lookarounds)
+pub fn regex_to_grammar(pattern: &str) -> Result<String> {
+    let branches = parse(pattern, false)?;
+    let exprs = branches.into_iter().map(|b| b.expr).collect();
+    Ok(format!("root ::= {}", alternatives(exprs)))
+}
+
+/// Convert a JSON Schema `pattern` into a GBNF expression for the inside of a JSON string
+/// Matched text is JSON escaped, and like JSON Schema the pattern is unanchored: unless a
+/// branch starts with `^` (or ends with `$`) any text can come before (or after) it.
+/// `any_char` is the rule for one character of a JSON string, escapes included
+/// # Errors
+/// Same as `regex_to_grammar`
+pub fn pattern_to_gbnf(pattern: &str, any_char: &str) -> Result<String> {
+    let branches = parse(pattern, true)?;
+    let exprs = branches
+        .into_iter()
+        .map(|branch| {
+            let mut parts = Vec::new();
+            if !branch.start {
+                parts.push(format!("{}*", any_char));
+            }
+            parts.push(branch.expr);
+            if !branch.end {
+                parts.push(format!("{}*", any_char));
+            }
+            parts.join(" ")
+        })
+        .collect();
+    Ok(alternatives(exprs))
+}
+
+/// A top level alternative of a pattern and whether it's anchored at either end
+struct Branch {
+    expr: String,
+    start: bool,
+    end: bool,
+}
+
+fn parse(pattern: &str, json: bool) -> Result<Vec<Branch>> {
+    let mut parser = RegexParser {
+        chars: pattern.chars().collect(),
+        pos: 0,
+        depth: 0,
+        json,
+    };
+    let mut branches = Vec::new();
+    loop {
+        let start = parser.eat('^');
+        let expr = parser.parse_sequence()?;
+        let end = parser.eat('$');
+        branches.push(Branch { expr, start, end });
+        match parser.peek() {
+            Some('|') => parser.pos += 1,
+            None => return Ok(branches),
+            Some(c) => bail!("unexpected {:?} at position {} in regex", c, parser.pos),
+        }
+    }
+}
+
+fn alternatives(mut exprs: Vec<String>) -> String {
+    if exprs.len() == 1 {
+        return exprs.remove(0);
+    }
+    format!("({})", exprs.join(" | "))
+}
+
+/// How a character is written inside a JSON string, without the quotes
+fn json_escaped(c: char) -> String {
+    let quoted = serde_json::to_string(&c.to_string()).expect("strings always serialize");
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Write a single character so it can sit inside a GBNF `[...]` class
+fn class_char(c: char) -> String {
+    match c {
+        ']' | '[' | '\\' | '^' | '-' | '"' => format!("\\x{:02X}", c as u32),
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        c if c.is_control() => format!("\\x{:02X}", c as u32),
+        _ => c.to_string(),
+    }
+}
+
+/// The ranges for a shorthand escape, with whether the shorthand is negated
+fn shorthand_class(c: char) -> Option<(&'static [(char, char)], bool)> {
+    const DIGIT: &[(char, char)] = &[('0', '9')];
+    const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+    const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r')];
+    match c {
+        'd' => Some((DIGIT, false)),
+        'D' => Some((DIGIT, true)),
+        'w' => Some((WORD, false)),
+        'W' => Some((WORD, true)),
+        's' => Some((SPACE, false)),
+        'S' => Some((SPACE, true)),
+        _ => None,
+    }
+}
+
+/// Characters a JSON string can't hold as they are
+fn is_json_special(c: char) -> bool {
+    c == '"' || c == '\\' || c < ' '
+}
+
+/// Split a range around the characters a JSON string has to escape
+fn without_json_specials(lo: char, hi: char) -> Vec<(char, char)> {
+    let mut out = Vec::new();
+    let mut lo = lo.max(' ');
+    for special in ['"', '\\'] {
+        if lo > hi {
+            break;
+        }
+        if (lo..=hi).contains(&special) {
+            if special > lo {
+                out.push((lo, char::from_u32(special as u32 - 1).unwrap()));
+            }
+            lo = char::from_u32(special as u32 + 1).unwrap();
+        }
+    }
+    if lo <= hi {
+        out.push((lo, hi));
+    }
+    out
+}
+
+struct CharClass {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+impl CharClass {
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != self.negated
+    }
+
+    fn ranges_text(ranges: &[(char, char)]) -> String {
+        ranges
+            .iter()
+            .map(|(lo, hi)| {
+                if lo == hi {
+                    class_char(*lo)
+                } else {
+                    format!("{}-{}", class_char(*lo), class_char(*hi))
+                }
+            })
+            .collect()
+    }
+
+    fn to_gbnf(&self, json: bool) -> Result<String> {
+        if !json {
+            let negation = if self.negated { "^" } else { "" };
+            return Ok(format!("[{}{}]", negation, Self::ranges_text(&self.ranges)));
+        }
+        let mut options = Vec::new();
+        if self.negated {
+            options.push(format!(
+                "[^{}\\x22\\x5C\\x00-\\x1F]",
+                Self::ranges_text(&self.ranges)
+            ));
+        } else {
+            let plain: Vec<(char, char)> = self
+                .ranges
+                .iter()
+                .flat_map(|(lo, hi)| without_json_specials(*lo, *hi))
+                .collect();
+            if !plain.is_empty() {
+                options.push(format!("[{}]", Self::ranges_text(&plain)));
+            }
+        }
+        // Negated classes only let the common control characters through, written as escapes
+        let escapable: Vec<char> = if self.negated {
+            vec!['"', '\\', '\t', '\n', '\r']
+        } else {
+            ('\0'..=' ').chain(['"', '\\']).collect()
+        };
+        for c in escapable {
+            if is_json_special(c) && self.contains(c) {
+                options.push(gbnf_literal(&json_escaped(c)));
+            }
+        }
+        match options.len() {
+            0 => bail!("character class in regex can't match anything"),
+            1 => Ok(options.remove(0)),
+            _ => Ok(format!("({})", options.join(" | "))),
+        }
+    }
+}
+
+struct RegexParser {
+    chars: Vec<char>,
+    pos: usize,
+    /// How many groups the parser is inside of
+    depth: usize,
+    /// Write matched text the way it appears inside a JSON string
+    json: bool,
+}
+
+impl RegexParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+            None => bail!("regex ended unexpectedly"),
+        }
+    }
+
+    fn literal(&self, c: char) -> String {
+        if self.json {
+            gbnf_literal(&json_escaped(c))
+        } else {
+            gbnf_literal(&c.to_string())
+        }
+    }
+
+    fn parse_alternation(&mut self) -> Result<String> {
+        let mut branches = vec![self.parse_sequence()?];
+        while self.eat('|') {
+            branches.push(self.parse_sequence()?);
+        }
+        Ok(alternatives(branches))
+    }
+
+    fn parse_sequence(&mut self) -> Result<String> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            if c == '$' {
+                let next = self.chars.get(self.pos + 1);
+                if self.depth == 0 && matches!(next, None | Some('|')) {
+                    break;
+                }
+                bail!("$ is only supported at the end of the pattern or of a top level branch");
+            }
+            let atom = self.parse_atom()?;
+            items.push(self.parse_quantifier(atom)?);
+        }
+        if items.is_empty() {
+            // GBNF has no empty literal, an optional empty group does the same job
+            return Ok("(\"\")?".to_string());
+        }
+        Ok(items.join(" "))
+    }
+
+    /// The character after a `\`, for escapes that stand for a single character
+    fn parse_escape(&mut self) -> Result<char> {
+        let c = self.next()?;
+        let hex_digits = match c {
+            'n' => return Ok('\n'),
+            't' => return Ok('\t'),
+            'r' => return Ok('\r'),
+            'f' => return Ok('\x0C'),
+            'v' => return Ok('\x0B'),
+            'x' => 2,
+            'u' => 4,
+            c if c.is_ascii_digit() || c == 'b' || c == 'B' => {
+                bail!("backreferences and word boundaries are not supported in regex grammars")
+            }
+            c if c.is_ascii_alphanumeric() => bail!("unsupported escape \\{} in regex", c),
+            c => return Ok(c),
+        };
+        let mut code = 0;
+        for _ in 0..hex_digits {
+            let digit = self.next()?;
+            code = code * 16
+                + digit
+                    .to_digit(16)
+                    .ok_or_else(|| anyhow!("invalid hex escape in regex"))?;
+        }
+        char::from_u32(code).ok_or_else(|| anyhow!("invalid character escape in regex"))
+    }
+
+    fn parse_atom(&mut self) -> Result<String> {
+        match self.next()? {
+            '(' => {
+                if self.eat('?') && self.next()? != ':' {
+                    bail!("lookarounds and named groups are not supported in regex grammars");
+                }
+                self.depth += 1;
+                let inner = self.parse_alternation()?;
+                self.depth -= 1;
+                if self.next()? != ')' {
+                    bail!("unclosed group in regex");
+                }
+                Ok(format!("({})", inner))
+            }
+            '[' => self.parse_class()?.to_gbnf(self.json),
+            '.' => CharClass {
+                negated: true,
+                ranges: vec![('\n', '\n')],
+            }
+            .to_gbnf(self.json),
+            '\\' => {
+                if let Some((ranges, negated)) = self.peek().and_then(shorthand_class) {
+                    self.pos += 1;
+                    let class = CharClass {
+                        negated,
+                        ranges: ranges.to_vec(),
+                    };
+                    return class.to_gbnf(self.json);
+                }
+                let c = self.parse_escape()?;
+                Ok(self.literal(c))
+            }
+            '^' => {
+                bail!("^ is only supported at the start of the pattern or of a top level branch")
+            }
+            c @ ('*' | '+' | '?' | '{') => bail!("nothing to repeat before {:?} in regex", c),
+            c => Ok(self.literal(c)),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<CharClass> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next()?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let start = if c == '\\' {
+                if let Some((shorthand, negated)) = self.peek().and_then(shorthand_class) {
+                    if negated {
+                        bail!("negated shorthands are not supported inside a regex class");
+                    }
+                    self.pos += 1;
+                    ranges.extend_from_slice(shorthand);
+                    continue;
+                }
+                self.parse_escape()?
+            } else {
+                c
+            };
+            // A dash right before the closing bracket is just a dash
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                let mut end = self.next()?;
+                if end == '\\' {
+                    end = self.parse_escape()?;
+                }
+                if end < start {
+                    bail!("invalid range {}-{} in regex class", start, end);
+                }
+                ranges.push((start, end));
+            } else {
+                ranges.push((start, start));
+            }
+        }
+        Ok(CharClass { negated, ranges })
+    }
+
+    fn parse_quantifier(&mut self, atom: String) -> Result<String> {
+        let quantified = match self.peek() {
+            Some('*') => format!("({})*", atom),
+            Some('+') => format!("({})+", atom),
+            Some('?') => format!("({})?", atom),
+            Some('{') => {
+                let close = self.chars[self.pos..]
+                    .iter()
+                    .position(|c| *c == '}')
+                    .map(|i| self.pos + i);
+                let Some(close) = close else {
+                    bail!("unclosed repetition in regex");
+                };
+                let body: String = self.chars[self.pos + 1..close].iter().collect();
+                let bound = |text: &str| {
+                    text.trim()
+                        .parse::<usize>()
+                        .map_err(|_| anyhow!("invalid repetition {{{}}} in regex", body))
+                };
+                let (min, max) = match body.split_once(',') {
+                    Some((min, max)) => {
+                        let min = if min.trim().is_empty() {
+                            0
+                        } else {
+                            bound(min)?
+                        };
+                        let max = if max.trim().is_empty() {
+                            None
+                        } else {
+                            Some(bound(max)?)
+                        };
+                        (min, max)
+                    }
+                    None => {
+                        let n = bound(&body)?;
+                        (n, Some(n))
+                    }
+                };
+                self.pos = close;
+                format!("({})", repeat(&atom, min, max, "")?)
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        // Lazy and possessive suffixes don't change what can match
+        if matches!(self.peek(), Some('?') | Some('+')) {
+            self.pos += 1;
+        }
+        Ok(quantified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gbnf(pattern: &str) -> String {
+        regex_to_grammar(pattern)
+            .unwrap()
+            .strip_prefix("root ::= ")
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn literals_classes_and_quantifiers() {
+        assert_eq!(gbnf("ab"), r#""a" "b""#);
+        assert_eq!(gbnf("[a-c_]+"), r#"([a-c_])+"#);
+        assert_eq!(gbnf(r"\d{2,3}"), r#"([0-9] [0-9] ([0-9])?)"#);
+        assert_eq!(gbnf(r"a{,2}"), r#"(("a" ("a")?)?)"#);
+        assert_eq!(gbnf("(?:a|b)*?"), r#"((("a" | "b")))*"#);
+        assert_eq!(gbnf(r"\x41é"), r#""A" "é""#);
+    }
+
+    #[test]
+    fn anchors_only_at_the_ends_of_top_level_branches() {
+        assert_eq!(gbnf("^a$"), r#""a""#);
+        assert_eq!(gbnf("^a|b$"), r#"("a" | "b")"#);
+        assert!(regex_to_grammar("a^b").is_err());
+        assert!(regex_to_grammar("a$b").is_err());
+        assert!(regex_to_grammar("(^a)").is_err());
+        assert!(regex_to_grammar("(a$)").is_err());
+        assert_eq!(gbnf(r"\$\^"), r#""$" "^""#);
+    }
+
+    #[test]
+    fn unsupported_constructs_are_errors() {
+        for pattern in [
+            r"(a)\1", r"\bword", "(?=a)", r"\p{L}", "a{x}", "[z-a]", "(a", "a)",
+        ] {
+            assert!(regex_to_grammar(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn wide_repetitions_are_errors() {
+        assert!(regex_to_grammar("a{0,64}").is_ok());
+        assert!(regex_to_grammar("a{0,65}").is_err());
+        assert!(regex_to_grammar("a{1,100}").is_err());
+    }
+
+    #[test]
+    fn patterns_are_unanchored_unless_marked() {
+        assert_eq!(pattern_to_gbnf("a", "char").unwrap(), r#"char* "a" char*"#);
+        assert_eq!(pattern_to_gbnf("^a", "char").unwrap(), r#""a" char*"#);
+        assert_eq!(
+            pattern_to_gbnf("^a$|b", "char").unwrap(),
+            r#"("a" | char* "b" char*)"#
+        );
+    }
+
+    #[test]
+    fn patterns_are_json_escaped() {
+        assert_eq!(
+            pattern_to_gbnf(r#"^"\\$"#, "c").unwrap(),
+            r#""\\\"" "\\\\""#
+        );
+        assert_eq!(pattern_to_gbnf(r"^\n$", "c").unwrap(), r#""\\n""#);
+        assert_eq!(
+            pattern_to_gbnf("^.$", "c").unwrap(),
+            r#"([^\n\x22\x5C\x00-\x1F] | "\\\"" | "\\\\" | "\\t" | "\\r")"#
+        );
+        assert_eq!(
+            pattern_to_gbnf(r#"^[a"]$"#, "c").unwrap(),
+            r#"([a] | "\\\"")"#
+        );
+        assert_eq!(
+            pattern_to_gbnf(r#"^[ -~]$"#, "c").unwrap(),
+            r#"([ -!#-\x5B\x5D-~] | "\\\"" | "\\\\")"#
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{bail, Context, Result};
+use llama_cpp_2::{llama_backend::LlamaBackend, model::AddBos, token::LlamaToken};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    embeddings::{decode_packed, embedding_context, generate_embeddings, InvalidInput},
+    types::{EmbeddingOptions, InputType, ModelState, PoolingType},
+};
+
+/// How the relevance scores were worked out
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RerankMethod {
+    /// A cross-encoder scoring each query/document pair
+    Rank,
+    /// Cosine similarity between the query and document embeddings
+    Embedding,
+}
+
+/// Score every document against the query
+/// # Arguments
+/// * `model` - A reranker (rank pooling) or an embedding model
+/// * `backend` - The llama backend
+/// * `query` - What the documents are ranked against
+/// * `documents` - The documents to score
+/// # Returns
+/// One score per document, in the same order as `documents`, and the method used
+/// # Errors
+/// If a query/document pair doesn't fit in the context window or decoding fails
+pub fn rerank(
+    model: &ModelState,
+    backend: &LlamaBackend,
+    query: &str,
+    documents: &[String],
+) -> Result<(Vec<f32>, RerankMethod)> {
+    if model.default_pooling() == PoolingType::Rank {
+        return Ok((
+            rank_scores(model, backend, query, documents)?,
+            RerankMethod::Rank,
+        ));
+    }
+    // Token level output has no single vector to compare, mean pool those models instead
+    let pooling = match model.default_pooling() {
+        PoolingType::None => PoolingType::Mean,
+        pooling => pooling,
+    };
+    let options = |input_type| EmbeddingOptions {
+        pooling: Some(pooling),
+        input_type: Some(input_type),
+        ..Default::default()
+    };
+    let query_embedding = generate_embeddings(
+        model,
+        backend,
+        &[query.to_string()],
+        &options(InputType::SearchQuery),
+    )?
+    .remove(0)
+    .embedding;
+    let embeddings = generate_embeddings(
+        model,
+        backend,
+        documents,
+        &options(InputType::SearchDocument),
+    )?;
+    let scores = embeddings
+        .iter()
+        .map(|e| cosine_similarity(&query_embedding, &e.embedding))
+        .collect();
+    Ok((scores, RerankMethod::Embedding))
+}
+
+/// Run each pair through the cross-encoder as `BOS query EOS SEP document EOS`
+fn rank_scores(
+    model: &ModelState,
+    backend: &LlamaBackend,
+    query: &str,
+    documents: &[String],
+) -> Result<Vec<f32>> {
+    let mut ctx = embedding_context(model, backend, PoolingType::Rank)?;
+    let n_ctx = ctx.n_ctx() as usize;
+    let eos = model.model.token_eos();
+    // llama.cpp writes this key with the typo
+    let sep = model
+        .metadata
+        .get("tokenizer.ggml.seperator_token_id")
+        .and_then(|v| v.as_u64())
+        .map(|id| LlamaToken::new(id as i32));
+    let query_tokens = model
+        .model
+        .str_to_token(query, AddBos::Always)
+        .with_context(|| "failed to tokenize query")?;
+    let mut pairs = Vec::with_capacity(documents.len());
+    for (i, document) in documents.iter().enumerate() {
+        let mut tokens = query_tokens.clone();
+        tokens.push(eos);
+        tokens.extend(sep);
+        tokens.extend(
+            model
+                .model
+                .str_to_token(document, AddBos::Never)
+                .with_context(|| format!("failed to tokenize document {}", i))?,
+        );
+        tokens.push(eos);
+        if tokens.len() > n_ctx {
+            bail!(InvalidInput(format!(
+                "Query and document {} ({} tokens) exceed the size of the context window ({})",
+                i,
+                tokens.len(),
+                n_ctx
+            )));
+        }
+        pairs.push(tokens);
+    }
+    let scored = decode_packed(&mut ctx, pairs.iter().map(|p| p.as_slice()), false, false)?;
+    scored
+        .into_iter()
+        .map(|r| {
+            r.embedding
+                .first()
+                .copied()
+                .context("reranker returned no score")
+        })
+        .collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+
+use llama_cpp_2::{
+    context::LlamaContext,
+    token::{data_array::LlamaTokenDataArray, LlamaToken},
+};
+use rand::{rngs::StdRng, Rng};
+
+use crate::types::SamplingParams;
+
+/// The tokens seen so far (prompt and generated) along with whether each one breaks a DRY match
+#[derive(Default)]
+pub struct TokenHistory {
+    pub tokens: Vec<LlamaToken>,
+    pub breakers: Vec<bool>,
+}
+
+impl TokenHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a token, `text` is its plain text form used to spot sequence breakers
+    pub fn push(&mut self, token: LlamaToken, text: &str, params: &SamplingParams) {
+        let is_breaker = params
+            .dry_sequence_breakers
+            .iter()
+            .any(|b| !b.is_empty() && text.contains(b.as_str()));
+        self.tokens.push(token);
+        self.breakers.push(is_breaker);
+    }
+}
+
+/// The most tokens DRY looks back over, every step compares the tail against each of them
+/// so a whole long context would make each token cost the square of its length
+const MAX_DRY_WINDOW: usize = 1024;
+
+/// Mirostat 1.0 estimates the Zipf exponent from this many of the most likely tokens
+const MIROSTAT_V1_M: usize = 100;
+
+/// The part of the history a penalty looks at, 0 means the whole history
+fn window(len: usize, last_n: i32) -> usize {
+    if last_n <= 0 {
+        0
+    } else {
+        len.saturating_sub(last_n as usize)
+    }
+}
+
+/// Apply the repeat, presence and frequency penalties to the candidates
+/// Matches the llama.cpp semantics: the repeat penalty scales the logit and the
+/// other two are subtracted based on how often the token shows up in the window
+pub fn apply_penalties(
+    candidates: &mut LlamaTokenDataArray,
+    history: &TokenHistory,
+    params: &SamplingParams,
+) {
+    if params.repeat_penalty == 1.0
+        && params.presence_penalty == 0.0
+        && params.frequency_penalty == 0.0
+    {
+        return;
+    }
+    let start = window(history.tokens.len(), params.repeat_last_n);
+    let mut counts: HashMap<LlamaToken, i32> = HashMap::new();
+    for token in &history.tokens[start..] {
+        *counts.entry(*token).or_insert(0) += 1;
+    }
+    for data in candidates.data.iter_mut() {
+        let Some(count) = counts.get(&data.id()) else {
+            continue;
+        };
+        let mut logit = data.logit();
+        if logit > 0.0 {
+            logit /= params.repeat_penalty;
+        } else {
+            logit *= params.repeat_penalty;
+        }
+        logit -= *count as f32 * params.frequency_penalty + params.presence_penalty;
+        data.set_logit(logit);
+    }
+    candidates.sorted = false;
+}
+
+/// Apply the DRY (don't repeat yourself) penalty
+/// For every earlier spot where the tail of the history already appeared, the token that
+/// followed it gets penalised by `multiplier * base^(match_len - allowed_length)`
+/// Matches never extend across a sequence breaker token
+/// Only the last `MAX_DRY_WINDOW` tokens are searched, however big `dry_penalty_last_n` is
+pub fn apply_dry(
+    candidates: &mut LlamaTokenDataArray,
+    history: &TokenHistory,
+    params: &SamplingParams,
+) {
+    if params.dry_multiplier <= 0.0 || params.dry_base < 1.0 {
+        return;
+    }
+    let last_n = match params.dry_penalty_last_n {
+        n if n <= 0 => MAX_DRY_WINDOW,
+        n => (n as usize).min(MAX_DRY_WINDOW),
+    };
+    let start = window(history.tokens.len(), last_n as i32);
+    let tokens = &history.tokens[start..];
+    let breakers = &history.breakers[start..];
+    let n = tokens.len();
+    if n < 2 || breakers[n - 1] {
+        return;
+    }
+    let allowed_length = params.dry_allowed_length.max(1) as usize;
+    let mut max_match: HashMap<LlamaToken, usize> = HashMap::new();
+    // `k` is the token that came right after an earlier copy of the current tail
+    for k in 1..n {
+        if breakers[k] {
+            continue;
+        }
+        let mut len = 0;
+        while len < k && tokens[k - 1 - len] == tokens[n - 1 - len] && !breakers[k - 1 - len] {
+            len += 1;
+        }
+        if len >= allowed_length {
+            let entry = max_match.entry(tokens[k]).or_insert(0);
+            *entry = (*entry).max(len);
+        }
+    }
+    if max_match.is_empty() {
+        return;
+    }
+    for data in candidates.data.iter_mut() {
+        if let Some(len) = max_match.get(&data.id()) {
+            let exponent = (*len - allowed_length) as i32;
+            let penalty = params.dry_multiplier * params.dry_base.powi(exponent);
+            data.set_logit(data.logit() - penalty);
+        }
+    }
+    candidates.sorted = false;
+}
+
+/// Run the truncation samplers and pick the next token
+/// `mu` carries the mirostat state between calls, it should start at `2 * tau`
+pub fn sample_token(
+    ctx: &mut LlamaContext,
+    candidates: &mut LlamaTokenDataArray,
+    params: &SamplingParams,
+    rng: &mut StdRng,
+    mu: &mut f32,
+) -> LlamaToken {
+    if params.temperature <= 0.0 {
+        return greedy(candidates);
+    }
+    if params.mirostat != 0 {
+        ctx.sample_temp(candidates, params.temperature);
+        // 1 is the original algorithm, 2 (or any other non zero mode) is 2.0
+        return match params.mirostat {
+            1 => sample_mirostat_v1(ctx, candidates, params, rng, mu),
+            _ => sample_mirostat_v2(ctx, candidates, params, rng, mu),
+        };
+    }
+    if params.top_k > 0 {
+        ctx.sample_top_k(candidates, params.top_k, 1);
+    }
+    if params.tfs_z < 1.0 {
+        ctx.sample_tail_free(candidates, params.tfs_z, 1);
+    }
+    if params.typical_p < 1.0 {
+        ctx.sample_typical(candidates, params.typical_p, 1);
+    }
+    if params.top_p < 1.0 {
+        ctx.sample_top_p(candidates, params.top_p, 1);
+    }
+    if params.min_p > 0.0 {
+        ctx.sample_min_p(candidates, params.min_p, 1);
+    }
+    ctx.sample_temp(candidates, params.temperature);
+    ctx.sample_token_softmax(candidates);
+    pick(candidates, rng)
+}
+
+fn greedy(candidates: &LlamaTokenDataArray) -> LlamaToken {
+    candidates
+        .data
+        .iter()
+        .max_by(|a, b| a.logit().total_cmp(&b.logit()))
+        .map(|d| d.id())
+        .expect("no candidates to sample from")
+}
+
+/// Draw a token from candidates that already hold normalised probabilities
+fn pick(candidates: &LlamaTokenDataArray, rng: &mut StdRng) -> LlamaToken {
+    let total: f32 = candidates.data.iter().map(|d| d.p()).sum();
+    let mut target = rng.gen::<f32>() * total;
+    for data in &candidates.data {
+        target -= data.p();
+        if target <= 0.0 {
+            return data.id();
+        }
+    }
+    greedy(candidates)
+}
+
+/// How many of the candidates mirostat 1.0 keeps, `probabilities` sorted from most likely
+/// Fits the Zipf exponent `s` to the top tokens and keeps the `k` that gives a surprise of `mu`
+fn mirostat_v1_keep(probabilities: &[f32], n_vocab: usize, mu: f32) -> usize {
+    let (mut sum_ti_bi, mut sum_ti_sq) = (0.0_f32, 0.0_f32);
+    for (i, pair) in probabilities.windows(2).take(MIROSTAT_V1_M - 1).enumerate() {
+        let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+        let b_i = (pair[0] / pair[1]).ln();
+        sum_ti_bi += t_i * b_i;
+        sum_ti_sq += t_i * t_i;
+    }
+    let s_hat = sum_ti_bi / sum_ti_sq;
+    let epsilon_hat = s_hat - 1.0;
+    let k = ((epsilon_hat * 2.0_f32.powf(mu)) / (1.0 - (n_vocab as f32).powf(-epsilon_hat)))
+        .powf(1.0 / s_hat);
+    // A NaN (too few candidates to fit) casts to 0, keeping just the top token
+    (k as usize).clamp(1, probabilities.len().max(1))
+}
+
+/// Mirostat 1.0, `mirostat: 1`
+fn sample_mirostat_v1(
+    ctx: &mut LlamaContext,
+    candidates: &mut LlamaTokenDataArray,
+    params: &SamplingParams,
+    rng: &mut StdRng,
+    mu: &mut f32,
+) -> LlamaToken {
+    let n_vocab = candidates.data.len();
+    ctx.sample_token_softmax(candidates);
+    let probabilities: Vec<f32> = candidates.data.iter().map(|d| d.p()).collect();
+    candidates
+        .data
+        .truncate(mirostat_v1_keep(&probabilities, n_vocab, *mu));
+    ctx.sample_token_softmax(candidates);
+    let token = pick(candidates, rng);
+    update_mu(candidates, token, params, mu);
+    token
+}
+
+/// Move `mu` towards the target surprise after picking `token`
+fn update_mu(
+    candidates: &LlamaTokenDataArray,
+    token: LlamaToken,
+    params: &SamplingParams,
+    mu: &mut f32,
+) {
+    let p = candidates
+        .data
+        .iter()
+        .find(|d| d.id() == token)
+        .map_or(1.0, |d| d.p());
+    let surprise = -p.log2();
+    *mu -= params.mirostat_eta * (surprise - params.mirostat_tau);
+}
+
+/// Mirostat 2.0, `mirostat: 2`
+fn sample_mirostat_v2(
+    ctx: &mut LlamaContext,
+    candidates: &mut LlamaTokenDataArray,
+    params: &SamplingParams,
+    rng: &mut StdRng,
+    mu: &mut f32,
+) -> LlamaToken {
+    ctx.sample_token_softmax(candidates);
+    // Candidates are sorted by probability, drop everything more surprising than mu
+    let keep = candidates
+        .data
+        .iter()
+        .position(|d| -d.p().log2() > *mu)
+        .unwrap_or(candidates.data.len())
+        .max(1);
+    candidates.data.truncate(keep);
+    ctx.sample_token_softmax(candidates);
+    let token = pick(candidates, rng);
+    update_mu(candidates, token, params, mu);
+    token
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use llama_cpp_2::token::data::LlamaTokenData;
+
+    fn candidates(logits: &[f32]) -> LlamaTokenDataArray {
+        LlamaTokenDataArray::from_iter(
+            logits
+                .iter()
+                .enumerate()
+                .map(|(i, logit)| LlamaTokenData::new(LlamaToken::new(i as i32), *logit, 0.0)),
+            true,
+        )
+    }
+
+    fn history(tokens: &[i32], params: &SamplingParams) -> TokenHistory {
+        let mut history = TokenHistory::new();
+        for token in tokens {
+            history.push(LlamaToken::new(*token), "x", params);
+        }
+        history
+    }
+
+    fn logits(candidates: &LlamaTokenDataArray) -> Vec<f32> {
+        candidates.data.iter().map(|d| d.logit()).collect()
+    }
+
+    #[test]
+    fn window_keeps_the_last_n_tokens() {
+        assert_eq!(window(10, 0), 0);
+        assert_eq!(window(10, -1), 0);
+        assert_eq!(window(10, 4), 6);
+        assert_eq!(window(3, 4), 0);
+    }
+
+    #[test]
+    fn history_marks_sequence_breakers() {
+        let params = SamplingParams::default();
+        let mut history = TokenHistory::new();
+        history.push(LlamaToken::new(1), "word", &params);
+        history.push(LlamaToken::new(2), "a: b", &params);
+        assert_eq!(history.breakers, vec![false, true]);
+    }
+
+    #[test]
+    fn repeat_penalty_scales_towards_zero() {
+        let params = SamplingParams {
+            repeat_penalty: 2.0,
+            ..Default::default()
+        };
+        let mut c = candidates(&[4.0, -4.0, 4.0]);
+        apply_penalties(&mut c, &history(&[0, 1], &params), &params);
+        assert_eq!(logits(&c), vec![2.0, -8.0, 4.0]);
+        assert!(!c.sorted);
+    }
+
+    #[test]
+    fn presence_and_frequency_penalties_subtract() {
+        let params = SamplingParams {
+            repeat_penalty: 1.0,
+            presence_penalty: 0.5,
+            frequency_penalty: 1.0,
+            ..Default::default()
+        };
+        let mut c = candidates(&[1.0, 1.0, 1.0]);
+        apply_penalties(&mut c, &history(&[0, 0, 0, 1], &params), &params);
+        assert_eq!(logits(&c), vec![-2.5, -0.5, 1.0]);
+    }
+
+    #[test]
+    fn penalties_only_look_at_the_window() {
+        let params = SamplingParams {
+            repeat_penalty: 1.0,
+            presence_penalty: 1.0,
+            repeat_last_n: 1,
+            ..Default::default()
+        };
+        let mut c = candidates(&[1.0, 1.0]);
+        apply_penalties(&mut c, &history(&[0, 1], &params), &params);
+        assert_eq!(logits(&c), vec![1.0, 0.0]);
+    }
+
+    #[test]
+    fn dry_penalises_the_token_that_continued_a_repeat() {
+        let params = SamplingParams {
+            dry_multiplier: 1.0,
+            dry_base: 2.0,
+            dry_allowed_length: 2,
+            ..Default::default()
+        };
+        // 1 2 3 4 ... 1 2 3: the tail "1 2 3" was followed by 4 before
+        let mut c = candidates(&[0.0; 6]);
+        apply_dry(
+            &mut c,
+            &history(&[1, 2, 3, 4, 5, 1, 2, 3], &params),
+            &params,
+        );
+        assert_eq!(logits(&c), vec![0.0, 0.0, 0.0, 0.0, -2.0, 0.0]);
+    }
+
+    #[test]
+    fn dry_ignores_matches_shorter_than_allowed() {
+        let params = SamplingParams {
+            dry_multiplier: 1.0,
+            dry_allowed_length: 3,
+            ..Default::default()
+        };
+        let mut c = candidates(&[0.0; 6]);
+        apply_dry(&mut c, &history(&[1, 2, 4, 5, 1, 2], &params), &params);
+        assert_eq!(logits(&c), vec![0.0; 6]);
+    }
+
+    #[test]
+    fn dry_only_searches_the_capped_window() {
+        let params = SamplingParams {
+            dry_multiplier: 1.0,
+            dry_allowed_length: 2,
+            ..Default::default()
+        };
+        // The earlier "1 2 3 4" is further back than the window reaches
+        let mut tokens = vec![1, 2, 3, 4];
+        tokens.resize(4 + MAX_DRY_WINDOW, 5);
+        tokens.extend([1, 2, 3]);
+        let mut c = candidates(&[0.0; 6]);
+        apply_dry(&mut c, &history(&tokens, &params), &params);
+        assert_eq!(logits(&c)[4], 0.0);
+    }
+
+    #[test]
+    fn mirostat_v1_keeps_more_tokens_for_a_higher_target_surprise() {
+        // Zipf distributed probabilities with an exponent of about 1.1
+        let probabilities: Vec<f32> = (1..=200).map(|i| (i as f32).powf(-1.1)).collect();
+        let total: f32 = probabilities.iter().sum();
+        let probabilities: Vec<f32> = probabilities.iter().map(|p| p / total).collect();
+        let low = mirostat_v1_keep(&probabilities, 32000, 4.0);
+        let high = mirostat_v1_keep(&probabilities, 32000, 8.0);
+        assert!(low >= 1 && low < high, "{} {}", low, high);
+        assert!(high <= probabilities.len());
+        assert_eq!(mirostat_v1_keep(&[1.0], 32000, 10.0), 1);
+    }
+
+    #[test]
+    fn dry_is_off_by_default() {
+        let params = SamplingParams::default();
+        let mut c = candidates(&[0.0; 3]);
+        apply_dry(&mut c, &history(&[1, 2, 1, 2], &params), &params);
+        assert_eq!(logits(&c), vec![0.0; 3]);
+        assert!(c.sorted);
+    }
+}
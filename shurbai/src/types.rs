@@ -1,8 +1,15 @@
 use llama_cpp_2::{
-    context::LlamaContext, llama_backend::LlamaBackend, model::LlamaModel, token::LlamaToken,
+    context::LlamaContext,
+    llama_backend::LlamaBackend,
+    model::{AddBos, LlamaLoraAdapter, LlamaModel},
+    token::LlamaToken,
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, time::Duration};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::gguf::GgufMetadata;
+use crate::presets::PresetTemplate;
 
 pub struct LlamaResult {
     pub n_tokens: i32,
@@ -24,35 +31,199 @@ impl LlamaResult {
     }
 }
 
+/// The embedding of a single input
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingResult {
+    /// The pooled embedding, empty when pooling is `none`
+    pub embedding: Vec<f32>,
+    /// One embedding per token, only filled when pooling is `none`
+    pub token_embeddings: Vec<Vec<f32>>,
+    /// One embedding per window, only filled when chunking without aggregation
+    pub chunks: Vec<ChunkEmbedding>,
+    pub n_tokens: usize,
+}
+
+/// The embedding of one window of a long input, `start..end` are token offsets into the input
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkEmbedding {
+    pub start: usize,
+    pub end: usize,
+    pub embedding: Vec<f32>,
+}
+
+/// How chunk embeddings are combined into one vector
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkAggregation {
+    Mean,
+    Max,
+}
+
+/// Split inputs into overlapping token windows instead of rejecting long ones
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkingOptions {
+    pub window: Option<usize>,  // default: the context size
+    pub overlap: Option<usize>, // default: 0
+    /// Combine the chunks into a single vector, otherwise every chunk is returned
+    pub aggregate: Option<ChunkAggregation>,
+}
+
+/// How token embeddings are combined into a single vector
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolingType {
+    None,
+    Mean,
+    Cls,
+    Last,
+    /// Cross-encoder relevance score, for reranker models
+    Rank,
+}
+
+impl PoolingType {
+    /// Map the `{arch}.pooling_type` GGUF value onto a pooling type
+    pub fn from_gguf(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(PoolingType::None),
+            1 => Some(PoolingType::Mean),
+            2 => Some(PoolingType::Cls),
+            3 => Some(PoolingType::Last),
+            4 => Some(PoolingType::Rank),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingNormalization {
+    L2,
+    None,
+}
+
+/// Per request embedding options, unset fields fall back to the model config
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EmbeddingOptions {
+    pub pooling: Option<PoolingType>,
+    pub normalization: Option<EmbeddingNormalization>,
+    pub chunking: Option<ChunkingOptions>,
+    /// Picks the instruction prefix from the model's `embedding_prefixes`
+    pub input_type: Option<InputType>,
+}
+
+/// What an embedding is going to be used for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputType {
+    SearchQuery,
+    SearchDocument,
+    Classification,
+    Clustering,
+}
+
+/// The task prefixes an embedding model was trained with, e.g. `search_query: ` for nomic-embed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EmbeddingPrefixes {
+    pub search_query: Option<String>,
+    pub search_document: Option<String>,
+    pub classification: Option<String>,
+    pub clustering: Option<String>,
+}
+
+impl EmbeddingPrefixes {
+    pub fn get(&self, input_type: InputType) -> Option<&str> {
+        match input_type {
+            InputType::SearchQuery => self.search_query.as_deref(),
+            InputType::SearchDocument => self.search_document.as_deref(),
+            InputType::Classification => self.classification.as_deref(),
+            InputType::Clustering => self.clustering.as_deref(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModelDefinition {
     pub path: String,
     pub name: String,
     pub config: ModelConfig,
-    pub chat_template: ChatTemplate,
+    #[serde(default)]
+    pub aliases: Vec<String>, // Other names requests can use for this model, e.g. after a rename
+    #[serde(default)]
+    pub fallbacks: Vec<String>, // Tried in order when this model can't be loaded or can't serve a request
+    #[serde(default)]
+    pub chat_template: Option<ChatTemplateConfig>, // default: the Jinja template embedded in the GGUF
+    #[serde(default)]
+    pub jinja_template: Option<String>, // default: the one embedded in the GGUF, for the jinja format
+    #[serde(default)]
+    pub template_format: Option<TemplateFormat>, // default: roles if only chat_template is set, otherwise jinja
+    #[serde(default)]
+    pub embedding_prefixes: EmbeddingPrefixes, // Only for embedding models that need them
+    #[serde(default)]
+    pub lora_adapters: Vec<LoraAdapterConfig>, // Loaded with the model, requests pick which apply
+}
+
+/// A LoRA adapter to load along with a model
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoraAdapterConfig {
+    pub path: String,
+    pub name: Option<String>, // default: the file name without its extension
+    pub scale: Option<f32>, // default: 1.0, 0 loads it for requests that ask for it but leaves it off otherwise
+}
+
+impl LoraAdapterConfig {
+    /// The name requests use to pick the adapter
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            std::path::Path::new(&self.path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_else(|| self.path.clone())
+        })
+    }
+}
+
+/// An adapter a request wants applied to its context
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoraSelection {
+    pub name: String,
+    pub scale: Option<f32>, // default: the scale from the model definition
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 /// Represents the configuration options for a model.
 pub struct ModelConfig {
-    is_embedding_model: bool,        // default: false
-    pub mirostat: Option<i32>,       // default: 0
-    pub mirostat_eta: Option<f32>,   // default: 0.1
-    pub mirostat_tau: Option<f32>,   // default: 5.0
-    pub use_gpu: Option<bool>,       // default: true
-    pub num_ctx: Option<i32>,        // default: 2048
-    pub num_gqa: Option<i32>,        // no default specified
-    pub main_gpu: Option<i32>,       // no default specified
-    pub use_mem_lock: Option<bool>,  // default: true
-    pub num_thread: Option<i32>,     // no default specified
-    pub repeat_last_n: Option<i32>,  // default: 64
+    #[serde(default)]
+    pub is_embedding_model: bool, // default: false
+    pub capabilities: Option<Vec<Capability>>, // default: worked out from is_embedding_model and the GGUF
+    pub mirostat: Option<i32>,                 // default: 0
+    pub mirostat_eta: Option<f32>,             // default: 0.1
+    pub mirostat_tau: Option<f32>,             // default: 5.0
+    pub use_gpu: Option<bool>,                 // default: true
+    pub num_ctx: Option<i32>,                  // default: 4096
+    pub num_gqa: Option<i32>,                  // no default specified
+    pub main_gpu: Option<i32>,                 // no default specified
+    pub use_mem_lock: Option<bool>,            // default: true
+    pub num_thread: Option<i32>,               // no default specified
+    pub keep_alive: Option<i64>, // default: 300, seconds to stay loaded when idle, negative to never unload
+    pub repeat_last_n: Option<i32>, // default: 64
     pub repeat_penalty: Option<f32>, // default: 1.1
-    pub temperature: Option<f32>,    // default: 0.8
-    pub seed: Option<i32>,           // default: 0
-    pub tfs_z: Option<f32>,          // default: 1
-    pub num_predict: Option<i32>,    // default: 128
-    pub top_k: Option<i32>,          // default: 40
-    pub top_p: Option<f32>,          // default: 0.9
+    pub temperature: Option<f32>, // default: 0.8
+    pub seed: Option<i32>,       // default: 0
+    pub tfs_z: Option<f32>,      // default: 1
+    pub num_predict: Option<i32>, // default: 128
+    pub top_k: Option<i32>,      // default: 40
+    pub top_p: Option<f32>,      // default: 0.9
+    pub min_p: Option<f32>,      // default: 0.0
+    pub typical_p: Option<f32>,  // default: 1.0
+    pub presence_penalty: Option<f32>, // default: 0.0
+    pub frequency_penalty: Option<f32>, // default: 0.0
+    pub dry_multiplier: Option<f32>, // default: 0.0 (disabled)
+    pub dry_base: Option<f32>,   // default: 1.75
+    pub dry_allowed_length: Option<i32>, // default: 2
+    pub dry_penalty_last_n: Option<i32>, // default: 0 (whole context, up to the last 1024 tokens)
+    pub dry_sequence_breakers: Option<Vec<String>>, // default: ["\n", ":", "\"", "*"]
+    pub pooling: Option<PoolingType>, // default: from the GGUF metadata, then mean
+    pub embedding_normalization: Option<EmbeddingNormalization>, // default: l2
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -66,19 +237,147 @@ pub struct ChatTemplate {
     pub assistant_prompt_template: String,
     pub stops: Vec<String>,
 }
+/// A model's per role templates, either written out in full or a preset with overrides
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ChatTemplateConfig {
+    Preset(PresetTemplate),
+    Custom(ChatTemplate),
+}
+
+impl ChatTemplateConfig {
+    pub fn template(&self) -> ChatTemplate {
+        match self {
+            ChatTemplateConfig::Preset(preset) => preset.template(),
+            ChatTemplateConfig::Custom(template) => template.clone(),
+        }
+    }
+}
+
+// By hand rather than untagged, so a bad preset name is reported as such instead of
+// as a custom template missing its fields
+impl<'de> Deserialize<'de> for ChatTemplateConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        if value.get("preset").is_some() {
+            serde_json::from_value(value)
+                .map(ChatTemplateConfig::Preset)
+                .map_err(serde::de::Error::custom)
+        } else {
+            serde_json::from_value(value)
+                .map(ChatTemplateConfig::Custom)
+                .map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+/// Which kind of template a model's chat prompts are built with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateFormat {
+    /// One TinyTemplate per role, from `chat_template`
+    Roles,
+    /// A Hugging Face style Jinja template, from `jinja_template` or the GGUF
+    Jinja,
+}
+
+/// A Jinja chat template and the special tokens it refers to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JinjaTemplate {
+    pub source: String,
+    pub bos_token: String,
+    pub eos_token: String,
+    pub stops: Vec<String>,
+}
+
+impl JinjaTemplate {
+    /// The template shipped in the GGUF, with its EOS and EOT tokens as stops
+    pub fn from_gguf(metadata: &GgufMetadata) -> Option<JinjaTemplate> {
+        let source = metadata.chat_template()?.to_string();
+        Some(Self::new(source, metadata))
+    }
+
+    /// `source` with the special tokens of the model it's for
+    pub fn new(source: String, metadata: &GgufMetadata) -> JinjaTemplate {
+        let token_text = |role: &str| {
+            metadata
+                .special_tokens
+                .get(role)
+                .and_then(|t| t.text.clone())
+        };
+        let mut stops: Vec<String> = ["eos", "eot", "eom"]
+            .iter()
+            .filter_map(|role| token_text(role))
+            .collect();
+        stops.dedup();
+        JinjaTemplate {
+            source,
+            bos_token: token_text("bos").unwrap_or_default(),
+            eos_token: token_text("eos").unwrap_or_default(),
+            stops,
+        }
+    }
+}
+
+/// How a model's chat messages are turned into a prompt
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptTemplate {
+    /// The per role templates from the model definition
+    Roles(ChatTemplate),
+    /// A Jinja template, the one from the GGUF when the definition doesn't have a template
+    Jinja(JinjaTemplate),
+}
+
+impl PromptTemplate {
+    /// Pick the template a model's chats use, `None` if the definition asks for one it doesn't have
+    pub fn resolve(
+        definition: &ModelDefinition,
+        metadata: &GgufMetadata,
+    ) -> Option<PromptTemplate> {
+        let format = definition.template_format.unwrap_or(
+            if definition.chat_template.is_some() && definition.jinja_template.is_none() {
+                TemplateFormat::Roles
+            } else {
+                TemplateFormat::Jinja
+            },
+        );
+        match format {
+            TemplateFormat::Roles => definition
+                .chat_template
+                .as_ref()
+                .map(|config| PromptTemplate::Roles(config.template())),
+            TemplateFormat::Jinja => match &definition.jinja_template {
+                Some(source) => Some(JinjaTemplate::new(source.clone(), metadata)),
+                None => JinjaTemplate::from_gguf(metadata),
+            }
+            .map(PromptTemplate::Jinja),
+        }
+    }
+
+    pub fn stops(&self) -> &Vec<String> {
+        match self {
+            PromptTemplate::Roles(template) => &template.stops,
+            PromptTemplate::Jinja(template) => &template.stops,
+        }
+    }
+}
+
 impl Default for ModelConfig {
     fn default() -> Self {
         ModelConfig {
             is_embedding_model: false,
+            capabilities: None,
             mirostat: Some(0),
             mirostat_eta: Some(0.1),
             mirostat_tau: Some(5.0),
             use_gpu: Some(true),
-            num_ctx: Some(2048),
+            num_ctx: Some(DEFAULT_NUM_CTX as i32),
             num_gqa: None,
             main_gpu: None,
             use_mem_lock: Some(true), // I like this on my default -- Fulton
             num_thread: None,
+            keep_alive: Some(300),
             repeat_last_n: Some(64),
             repeat_penalty: Some(1.1),
             temperature: Some(0.8),
@@ -87,17 +386,329 @@ impl Default for ModelConfig {
             num_predict: Some(128),
             top_k: Some(40),
             top_p: Some(0.9),
+            min_p: Some(0.0),
+            typical_p: Some(1.0),
+            presence_penalty: Some(0.0),
+            frequency_penalty: Some(0.0),
+            dry_multiplier: Some(0.0),
+            dry_base: Some(1.75),
+            dry_allowed_length: Some(2),
+            dry_penalty_last_n: Some(0),
+            dry_sequence_breakers: None,
+            pooling: None,
+            embedding_normalization: Some(EmbeddingNormalization::L2),
+        }
+    }
+}
+
+impl ModelConfig {
+    /// How many tokens each context made for this model holds
+    pub fn context_size(&self) -> usize {
+        self.num_ctx
+            .filter(|n| *n > 0)
+            .map_or(DEFAULT_NUM_CTX, |n| n as usize)
+    }
+
+    /// This config with every unset field filled in from `ModelConfig::default()`
+    pub fn effective(&self) -> ModelConfig {
+        let mut config = serde_json::to_value(self).expect("config always serializes");
+        let defaults =
+            serde_json::to_value(ModelConfig::default()).expect("config always serializes");
+        if let (Some(config), Some(defaults)) = (config.as_object_mut(), defaults.as_object()) {
+            for (key, value) in defaults {
+                if config.get(key).is_none_or(|v| v.is_null()) {
+                    config.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        serde_json::from_value(config).expect("filled config always deserializes")
+    }
+
+    /// Resolve the sampling options for this model, filling any unset field with its default
+    pub fn sampling_params(&self) -> SamplingParams {
+        let defaults = SamplingParams::default();
+        SamplingParams {
+            temperature: self.temperature.unwrap_or(defaults.temperature),
+            top_k: self.top_k.unwrap_or(defaults.top_k),
+            top_p: self.top_p.unwrap_or(defaults.top_p),
+            min_p: self.min_p.unwrap_or(defaults.min_p),
+            tfs_z: self.tfs_z.unwrap_or(defaults.tfs_z),
+            typical_p: self.typical_p.unwrap_or(defaults.typical_p),
+            repeat_last_n: self.repeat_last_n.unwrap_or(defaults.repeat_last_n),
+            repeat_penalty: self.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            presence_penalty: self.presence_penalty.unwrap_or(defaults.presence_penalty),
+            frequency_penalty: self.frequency_penalty.unwrap_or(defaults.frequency_penalty),
+            dry_multiplier: self.dry_multiplier.unwrap_or(defaults.dry_multiplier),
+            dry_base: self.dry_base.unwrap_or(defaults.dry_base),
+            dry_allowed_length: self
+                .dry_allowed_length
+                .unwrap_or(defaults.dry_allowed_length),
+            dry_penalty_last_n: self
+                .dry_penalty_last_n
+                .unwrap_or(defaults.dry_penalty_last_n),
+            dry_sequence_breakers: self
+                .dry_sequence_breakers
+                .clone()
+                .unwrap_or(defaults.dry_sequence_breakers),
+            mirostat: self.mirostat.unwrap_or(defaults.mirostat),
+            mirostat_tau: self.mirostat_tau.unwrap_or(defaults.mirostat_tau),
+            mirostat_eta: self.mirostat_eta.unwrap_or(defaults.mirostat_eta),
+            // A seed of 0 (or below) keeps the old behaviour of a fresh random seed per request
+            seed: self.seed.filter(|s| *s > 0).map(|s| s as u32),
+        }
+    }
+}
+
+/// A grammar the generated output has to follow
+#[derive(Debug, Clone)]
+pub struct GrammarConstraint {
+    pub grammar: String,
+    /// The output is a JSON object or array, stop as soon as it closes
+    pub json: bool,
+}
+
+impl GrammarConstraint {
+    pub fn new(grammar: String) -> Self {
+        GrammarConstraint {
+            grammar,
+            json: false,
+        }
+    }
+
+    pub fn json(grammar: String) -> Self {
+        GrammarConstraint {
+            grammar,
+            json: true,
+        }
+    }
+}
+
+/// The fully resolved sampling options used by a single call to `generate`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SamplingParams {
+    pub temperature: f32,
+    pub top_k: i32,
+    pub top_p: f32,
+    pub min_p: f32,
+    pub tfs_z: f32,
+    pub typical_p: f32,
+    pub repeat_last_n: i32,
+    pub repeat_penalty: f32,
+    pub presence_penalty: f32,
+    pub frequency_penalty: f32,
+    pub dry_multiplier: f32,
+    pub dry_base: f32,
+    pub dry_allowed_length: i32,
+    pub dry_penalty_last_n: i32,
+    pub dry_sequence_breakers: Vec<String>,
+    pub mirostat: i32,
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+    pub seed: Option<u32>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        SamplingParams {
+            temperature: 0.8,
+            top_k: 40,
+            top_p: 0.9,
+            min_p: 0.0,
+            tfs_z: 1.0,
+            typical_p: 1.0,
+            repeat_last_n: 64,
+            repeat_penalty: 1.1,
+            presence_penalty: 0.0,
+            frequency_penalty: 0.0,
+            dry_multiplier: 0.0,
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_penalty_last_n: 0,
+            dry_sequence_breakers: vec![
+                "\n".to_string(),
+                ":".to_string(),
+                "\"".to_string(),
+                "*".to_string(),
+            ],
+            mirostat: 0,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+            seed: None,
         }
     }
 }
 
+/// What a loaded model can be used for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    Chat,
+    Completion,
+    Embedding,
+    Infill,
+    Rerank,
+}
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::Chat => "chat",
+            Capability::Completion => "completion",
+            Capability::Embedding => "embedding",
+            Capability::Infill => "infill",
+            Capability::Rerank => "rerank",
+        }
+    }
+}
+
+/// An adapter as llama.cpp loaded it, wrapped so it can be `Send`
+pub struct LoadedLoraAdapter(pub LlamaLoraAdapter);
+
+// `LlamaLoraAdapter` holds a raw pointer, which is all that keeps it from being `Send`
+// llama.cpp doesn't tie an adapter to the thread that loaded it, and every use of it, from
+// attaching it to the end of the generation that reads it, happens under the lock below
+unsafe impl Send for LoadedLoraAdapter {}
+
+/// A loaded LoRA adapter
+pub struct LoraAdapter {
+    pub name: String,
+    pub scale: f32,
+    // Held from attaching it to a context until that context is done generating
+    pub adapter: Mutex<LoadedLoraAdapter>,
+}
+
 pub struct ModelState {
+    /// Declared before `model` so the adapters are freed before the model they belong to
+    pub lora_adapters: Vec<LoraAdapter>,
     pub model: LlamaModel,
     pub config: ModelConfig,
-    pub chat_template: ChatTemplate,
+    /// `None` for models without a template of their own or in their GGUF
+    pub chat_template: Option<PromptTemplate>,
+    pub metadata: GgufMetadata,
+    pub capabilities: Vec<Capability>,
+    pub embedding_prefixes: EmbeddingPrefixes,
+    /// The model file's path, size and modification time when it was loaded, tells apart
+    /// different weights that were served under the same name
+    pub file_signature: String,
 }
 
-pub struct ModelManager {
-    pub backend: LlamaBackend,
-    pub models: HashMap<String, ModelState>,
+/// Identify a model file by its path, size and modification time
+pub fn file_signature(path: &str) -> String {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return path.to_string();
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!("{}:{}:{}", path, metadata.len(), modified)
 }
+
+/// The context size generation uses when the model definition doesn't set `num_ctx`
+pub const DEFAULT_NUM_CTX: usize = 4096;
+
+impl ModelState {
+    /// How many tokens a generation context holds, prompt and output together
+    pub fn context_size(&self) -> usize {
+        self.config.context_size()
+    }
+
+    /// How many tokens `text` takes up as a prompt
+    /// # Errors
+    /// If the model fails to tokenize it
+    pub fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        Ok(self.model.str_to_token(text, AddBos::Always)?.len())
+    }
+
+    /// The adapters a request's context gets and the scale of each
+    /// Without a selection every adapter applies at the scale from the model definition
+    /// # Errors
+    /// If the selection names an adapter the model doesn't have
+    pub fn lora_scales(
+        &self,
+        selection: Option<&[LoraSelection]>,
+    ) -> Result<Vec<(&LoraAdapter, f32)>, String> {
+        let Some(selection) = selection else {
+            return Ok(self
+                .lora_adapters
+                .iter()
+                .filter(|a| a.scale != 0.0)
+                .map(|a| (a, a.scale))
+                .collect());
+        };
+        selection
+            .iter()
+            .map(|selected| {
+                let adapter = self
+                    .lora_adapters
+                    .iter()
+                    .find(|a| a.name == selected.name)
+                    .ok_or_else(|| {
+                        let names: Vec<&str> =
+                            self.lora_adapters.iter().map(|a| a.name.as_str()).collect();
+                        format!(
+                            "Model has no LoRA adapter {} (has: {})",
+                            selected.name,
+                            names.join(", ")
+                        )
+                    })?;
+                Ok((adapter, selected.scale.unwrap_or(adapter.scale)))
+            })
+            .collect()
+    }
+
+    /// The stop words for this model's template, generation also halts on any end of generation token
+    pub fn stops(&self) -> Vec<String> {
+        self.chat_template
+            .as_ref()
+            .map(|t| t.stops().clone())
+            .unwrap_or_default()
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Work out what a model can do, an explicit `capabilities` list in the config wins
+    pub fn detect_capabilities(config: &ModelConfig, metadata: &GgufMetadata) -> Vec<Capability> {
+        if let Some(capabilities) = &config.capabilities {
+            return capabilities.clone();
+        }
+        if config.is_embedding_model {
+            // A rank pooled model only outputs scores, so it can't serve embeddings
+            if Self::configured_pooling(config, metadata) == Some(PoolingType::Rank) {
+                return vec![Capability::Rerank];
+            }
+            return vec![Capability::Embedding];
+        }
+        let mut capabilities = vec![Capability::Chat, Capability::Completion];
+        let has_fim = [
+            "tokenizer.ggml.prefix_token_id",
+            "tokenizer.ggml.fim_pre_token_id",
+        ]
+        .iter()
+        .any(|key| metadata.get(key).is_some());
+        if has_fim {
+            capabilities.push(Capability::Infill);
+        }
+        capabilities
+    }
+
+    /// The pooling from the config, then the GGUF metadata
+    fn configured_pooling(config: &ModelConfig, metadata: &GgufMetadata) -> Option<PoolingType> {
+        config.pooling.or_else(|| {
+            metadata
+                .arch_value("pooling_type")
+                .and_then(|v| v.as_u64())
+                .and_then(PoolingType::from_gguf)
+        })
+    }
+
+    /// The pooling to use when a request doesn't ask for one
+    pub fn default_pooling(&self) -> PoolingType {
+        Self::configured_pooling(&self.config, &self.metadata).unwrap_or(PoolingType::Mean)
+    }
+}
+
+pub use crate::manager::ModelManager;